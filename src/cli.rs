@@ -0,0 +1,94 @@
+use crate::{
+    data,
+    sync::{self, SyncPlan},
+    worker::{EventSender, SyncJob},
+};
+use eframe::egui;
+use std::{
+    error::Error,
+    sync::{atomic::AtomicBool, mpsc, Arc},
+};
+
+// Release builds on Windows run without a console, so nothing printed would show up
+// unless the one of the shell that started the app is attached.
+#[cfg(windows)]
+fn attach_console() {
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn AttachConsole(process_id: u32) -> i32;
+    }
+    const ATTACH_PARENT_PROCESS: u32 = u32::MAX;
+    unsafe {
+        AttachConsole(ATTACH_PARENT_PROCESS);
+    }
+}
+
+#[cfg(not(windows))]
+fn attach_console() {}
+
+// `--dry-run [save...]`: prints what syncing each save, or only the named ones, would do.
+// Returns the exit code.
+pub fn dry_run(names: &[String]) -> i32 {
+    attach_console();
+    let config = data::load_config_data();
+    let mut code = 0;
+    for name in names {
+        if !config
+            .saves
+            .iter()
+            .any(|s| &s.name == name || &s.id == name)
+        {
+            eprintln!("No save named '{}'", name);
+            code = 1;
+        }
+    }
+    let saves = config
+        .saves
+        .iter()
+        .filter(|s| names.is_empty() || names.iter().any(|n| *n == s.name || *n == s.id));
+    for save in saves {
+        let job = SyncJob {
+            save: save.clone(),
+            ftp: config.ftp_config.clone(),
+            device: config.device.clone(),
+            max_retries: 0,
+            force: None,
+            dry_run: true,
+            link: save.name_link(&config.saves),
+        };
+        match preview(&job) {
+            Ok(plan) => print_plan(&save.name, &plan),
+            Err(err) => {
+                eprintln!("{}: {}", save.name, err);
+                code = 1;
+            }
+        }
+    }
+    code
+}
+
+fn preview(job: &SyncJob) -> Result<SyncPlan, Box<dyn Error>> {
+    // Nothing listens to the progress events, they are only kept until we return.
+    let (sender, _receiver) = mpsc::channel();
+    let events = EventSender::new(
+        0,
+        sender,
+        egui::Context::default(),
+        Arc::new(AtomicBool::new(false)),
+        Arc::new(AtomicBool::new(false)),
+    );
+    sync::preview_save(&events, job)
+}
+
+fn print_plan(name: &str, plan: &SyncPlan) {
+    println!("{}: {}", name, plan.describe());
+    for (mark, files) in [
+        ("+", &plan.added),
+        ("~", &plan.changed),
+        ("-", &plan.deleted),
+    ] {
+        for file in files {
+            println!("  {} {}", mark, file);
+        }
+    }
+}
@@ -1,24 +1,208 @@
-use std::{error::Error, fs, result::Result};
+use crate::{journal::Direction, paths, scheduler::SchedulerConfig};
+use std::{collections::HashMap, env, error::Error, fs, path::PathBuf, result::Result};
+
+// Extra folders are archived under this folder, each in a subfolder named after it.
+pub const ROOTS_DIR: &str = ".rc-roots";
 
 #[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct SaveUI {
+    #[serde(default = "new_id")]
+    pub id: String,
     pub name: String,
     pub path: String,
+    // Device id -> path, for machines where this save lives somewhere other than `path`.
+    #[serde(default)]
+    pub device_paths: HashMap<String, String>,
+    // gitignore-style patterns for files that are never synced.
+    #[serde(default)]
+    pub ignore: Vec<String>,
+    // Files bigger than this many MB are skipped; 0 means no limit.
+    #[serde(default)]
+    pub max_file_mb: u64,
+    #[serde(default)]
+    pub mode: SyncMode,
+    // More folders archived together with `path`, e.g. a config folder next to the saves.
+    #[serde(default)]
+    pub roots: Vec<SaveRoot>,
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct SaveRoot {
+    // Names the folder in the archive, so it has to match on every device.
+    pub name: String,
+    pub path: String,
+    #[serde(default)]
+    pub device_paths: HashMap<String, String>,
+}
+
+// A folder of a save on this device.
+pub struct LocalRoot {
+    // Where its files go in the archive; empty for the main folder.
+    pub prefix: String,
+    pub path: PathBuf,
+}
+
+impl LocalRoot {
+    pub fn archive_path(&self, relative: &str) -> String {
+        if self.prefix.is_empty() {
+            relative.to_string()
+        } else {
+            format!("{}/{}", self.prefix, relative)
+        }
+    }
+}
+
+pub fn valid_root_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\\'])
+}
+
+impl SaveUI {
+    pub fn new(name: String, path: String) -> Self {
+        Self {
+            id: new_id(),
+            name,
+            path,
+            device_paths: HashMap::new(),
+            ignore: Vec::new(),
+            max_file_mb: 0,
+            mode: SyncMode::TwoWay,
+            roots: Vec::new(),
+        }
+    }
+
+    // The unexpanded path this save uses on the given device.
+    pub fn device_path(&self, device_id: &str) -> &String {
+        self.device_paths.get(device_id).unwrap_or(&self.path)
+    }
+
+    pub fn local_path(&self, device_id: &str) -> String {
+        paths::expand(self.device_path(device_id))
+    }
+
+    // The `link` a sync starts with. Two saves on this device with the same name must
+    // never end up sharing a cloud folder, otherwise the user is asked.
+    pub fn name_link(&self, saves: &[SaveUI]) -> Option<bool> {
+        let same_name = saves.iter().filter(|s| s.name == self.name).count();
+        (same_name > 1).then_some(false)
+    }
+
+    // Every folder of the save on this device, the main one first. Extra folders that
+    // can't be used are left out.
+    pub fn local_roots(&self, device_id: &str) -> Vec<LocalRoot> {
+        let main = LocalRoot {
+            prefix: String::new(),
+            path: PathBuf::from(self.local_path(device_id)),
+        };
+        let extra = self.extra_roots(device_id).into_iter().flatten();
+        std::iter::once(main).chain(extra).collect()
+    }
+
+    // Each extra folder on this device, in order, or why it is left out. A folder that
+    // overlaps another one would be archived and restored twice.
+    pub fn extra_roots(&self, device_id: &str) -> Vec<Result<LocalRoot, &'static str>> {
+        let mut taken = vec![PathBuf::from(self.local_path(device_id))];
+        let mut names = Vec::new();
+        let mut roots = Vec::new();
+        for root in &self.roots {
+            let path = paths::expand(root.device_paths.get(device_id).unwrap_or(&root.path));
+            let path = PathBuf::from(path.trim());
+            let result = if !valid_root_name(&root.name) {
+                Err("Skipped until it has a name without slashes")
+            } else if names.contains(&root.name) {
+                Err("Skipped, another folder has this name")
+            } else if path.as_os_str().is_empty() {
+                Err("Skipped until it has a folder")
+            } else if taken
+                .iter()
+                .any(|t| path.starts_with(t) || t.starts_with(&path))
+            {
+                Err("Skipped, it overlaps the main folder or another one")
+            } else {
+                names.push(root.name.clone());
+                taken.push(path.clone());
+                Ok(LocalRoot {
+                    prefix: format!("{}/{}", ROOTS_DIR, root.name),
+                    path,
+                })
+            };
+            roots.push(result);
+        }
+        roots
+    }
+}
+
+// Which ways a save is copied. Backups only ever go up, mirrors only ever come down.
+#[derive(Clone, Copy, PartialEq, Default, serde::Serialize, serde::Deserialize)]
+pub enum SyncMode {
+    #[default]
+    TwoWay,
+    UploadOnly,
+    DownloadOnly,
+}
+
+impl SyncMode {
+    pub const ALL: [SyncMode; 3] = [
+        SyncMode::TwoWay,
+        SyncMode::UploadOnly,
+        SyncMode::DownloadOnly,
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            SyncMode::TwoWay => "Two-way",
+            SyncMode::UploadOnly => "Upload only",
+            SyncMode::DownloadOnly => "Download only",
+        }
+    }
+
+    pub fn allows(&self, direction: Direction) -> bool {
+        match self {
+            SyncMode::TwoWay => true,
+            SyncMode::UploadOnly => direction == Direction::Upload,
+            SyncMode::DownloadOnly => direction == Direction::Download,
+        }
+    }
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct Device {
+    pub id: String,
+    pub name: String,
+}
+
+impl Default for Device {
+    fn default() -> Self {
+        let name = env::var("HOSTNAME")
+            .or_else(|_| env::var("COMPUTERNAME"))
+            .or_else(|_| fs::read_to_string("/etc/hostname").map(|h| h.trim().to_string()))
+            .unwrap_or("Unknown device".to_string());
+        Self { id: new_id(), name }
+    }
+}
+
+pub fn new_id() -> String {
+    uuid::Uuid::new_v4().to_string()
 }
 
 const CONFIG_DIR: &str = ".rc";
 
+fn config_dir() -> PathBuf {
+    home::home_dir().unwrap().join(CONFIG_DIR)
+}
+
+// A file or folder in the app's config folder.
+pub fn config_path(name: &str) -> PathBuf {
+    config_dir().join(name)
+}
+
 pub fn purge_tmp_folder() -> Result<(), Box<dyn Error>> {
-    let mut path = home::home_dir().unwrap();
-    path.push(CONFIG_DIR);
-    path.push("tmp");
+    let path = config_path("tmp");
     fs::remove_dir_all(&path)?;
     fs::create_dir(&path)?;
     Ok(())
 }
 pub fn check_config_folder() {
-    let mut path = home::home_dir().unwrap();
-    path.push(CONFIG_DIR);
+    let path = config_dir();
     if !path.exists() {
         let _ = fs::create_dir(&path);
     }
@@ -26,14 +210,19 @@ pub fn check_config_folder() {
 
 #[derive(serde::Serialize, serde::Deserialize)]
 pub struct Json {
+    #[serde(default)]
+    pub device: Device,
     pub server: String,
     pub ftp_config: FtpDetails,
     pub saves: Vec<SaveUI>,
+    #[serde(default)]
+    pub scheduler: SchedulerConfig,
 }
 
 impl Default for Json {
     fn default() -> Self {
         Self {
+            device: Device::default(),
             server: "ftp".to_string(),
             ftp_config: FtpDetails {
                 ip: "".to_owned(),
@@ -42,6 +231,7 @@ impl Default for Json {
                 port: 21,
             },
             saves: Vec::new(),
+            scheduler: SchedulerConfig::default(),
         }
     }
 }
@@ -55,17 +245,19 @@ pub struct FtpDetails {
 }
 
 pub fn save_config_data(
+    device: &Device,
     server: String,
     ftp_details: &FtpDetails,
     saves: &Vec<SaveUI>,
+    scheduler: &SchedulerConfig,
 ) -> Result<(), Box<dyn Error>> {
-    let mut path = home::home_dir().unwrap();
-    path.push(CONFIG_DIR);
-    path.push("config.json");
+    let path = config_path("config.json");
     let json_data = Json {
+        device: device.clone(),
         server,
         ftp_config: ftp_details.clone(),
         saves: saves.to_vec(),
+        scheduler: scheduler.clone(),
     };
     let j = serde_json::to_string(&json_data)?;
     fs::write(&path, &j).expect("Unable to write file");
@@ -73,13 +265,28 @@ pub fn save_config_data(
 }
 
 pub fn load_config_data() -> Json {
-    let mut path = home::home_dir().unwrap();
-    path.push(CONFIG_DIR);
-    path.push("config.json");
+    let path = config_path("config.json");
     let file_result = fs::read(&path);
     let file_slice = match file_result {
         Ok(file) => file,
         Err(_error) => return Json::default(),
     };
-    serde_json::from_slice(&file_slice).unwrap()
+    let value: serde_json::Value = serde_json::from_slice(&file_slice).unwrap();
+    // Configs from before saves and devices had ids get new ones when loaded. They are
+    // written back straight away, so every later load, from the app or `--dry-run`, agrees.
+    let missing_ids = value.get("device").is_none()
+        || value["saves"]
+            .as_array()
+            .is_some_and(|saves| saves.iter().any(|save| save.get("id").is_none()));
+    let json: Json = serde_json::from_value(value).unwrap();
+    if missing_ids {
+        let _ = save_config_data(
+            &json.device,
+            json.server.clone(),
+            &json.ftp_config,
+            &json.saves,
+            &json.scheduler,
+        );
+    }
+    json
 }
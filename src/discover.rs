@@ -0,0 +1,170 @@
+use crate::{data, manifest, paths, steam};
+use eframe::egui;
+use std::{
+    path::PathBuf,
+    sync::mpsc::{self, Receiver},
+    thread,
+};
+
+// Found saves, plus notes about anything the scan had to skip.
+type ScanResult = Result<(Vec<data::SaveUI>, Vec<String>), String>;
+
+pub struct DiscoverWindow {
+    pub open: bool,
+    status: String,
+    candidates: Vec<(bool, data::SaveUI)>,
+    skipped: Vec<String>,
+    scan: Option<Receiver<ScanResult>>,
+}
+
+impl Default for DiscoverWindow {
+    fn default() -> Self {
+        Self {
+            open: false,
+            status: "".to_string(),
+            candidates: Vec::new(),
+            skipped: Vec::new(),
+            scan: None,
+        }
+    }
+}
+
+impl DiscoverWindow {
+    // Loading a full manifest takes a few seconds, so it runs off the UI thread.
+    pub fn start_manifest_scan(&mut self, manifest_path: PathBuf) {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let result = manifest::load_manifest(&manifest_path)
+                .map(|games| (manifest::scan(&games), Vec::new()))
+                .map_err(|err| err.to_string());
+            let _ = tx.send(result);
+        });
+        self.status = "Scanning for saves...".to_string();
+        self.candidates.clear();
+        self.skipped.clear();
+        self.scan = Some(rx);
+        self.open = true;
+    }
+
+    pub fn start_steam_scan(&mut self) {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let _ = tx.send(Ok(steam::scan_saves()));
+        });
+        self.status = "Scanning Steam libraries...".to_string();
+        self.candidates.clear();
+        self.skipped.clear();
+        self.scan = Some(rx);
+        self.open = true;
+    }
+
+    // Returns the saves the user chose to add this frame.
+    pub fn draw(
+        &mut self,
+        ctx: &egui::Context,
+        existing: &[data::SaveUI],
+        device: &data::Device,
+    ) -> Vec<data::SaveUI> {
+        let mut added = Vec::new();
+        if let Some(rx) = &self.scan {
+            if let Ok(result) = rx.try_recv() {
+                match result {
+                    Ok((found, skipped)) => {
+                        self.candidates = found
+                            .into_iter()
+                            .filter(|s| {
+                                !existing
+                                    .iter()
+                                    .any(|e| e.local_path(&device.id) == paths::expand(&s.path))
+                            })
+                            // Nothing is picked up until the user has looked at it.
+                            .map(|s| (false, s))
+                            .collect();
+                        self.status = format!("Found {} save locations", self.candidates.len());
+                        if !skipped.is_empty() {
+                            self.status += &format!(", skipped {} unreadable games", skipped.len());
+                        }
+                        self.skipped = skipped;
+                    }
+                    Err(err) => self.status = format!("Scan failed: {}", err),
+                }
+                self.scan = None;
+            } else {
+                ctx.request_repaint();
+            }
+        }
+        ctx.show_viewport_immediate(
+            egui::ViewportId::from_hash_of("discover_viewport"),
+            egui::ViewportBuilder::default()
+                .with_title("Discover Saves")
+                .with_inner_size([500.0, 400.0])
+                .with_resizable(false)
+                .with_decorations(false),
+            |ctx, class| {
+                assert!(
+                    class == egui::ViewportClass::Immediate,
+                    "This egui backend doesn't support multiple viewports"
+                );
+                let panel_frame = egui::Frame {
+                    fill: ctx.style().visuals.window_fill(),
+                    rounding: 5.0.into(),
+                    stroke: ctx.style().visuals.widgets.noninteractive.fg_stroke,
+                    outer_margin: 0.5.into(),
+                    inner_margin: 7.5.into(),
+                    ..Default::default()
+                };
+                egui::CentralPanel::default()
+                    .frame(panel_frame)
+                    .show(ctx, |ui| {
+                        let menu_bar_response = ui.interact(
+                            egui::Rect::from_points(&[
+                                egui::Pos2::new(0.0, 0.0),
+                                egui::Pos2::new(ui.max_rect().right(), 20.0),
+                            ]),
+                            egui::Id::new("discover_title_bar"),
+                            egui::Sense::click_and_drag(),
+                        );
+                        if menu_bar_response.drag_started_by(egui::PointerButton::Primary) {
+                            ui.ctx().send_viewport_cmd(egui::ViewportCommand::StartDrag);
+                        }
+                        egui::menu::bar(ui, |ui| {
+                            if ui.button("Select All").clicked() {
+                                self.candidates.iter_mut().for_each(|c| c.0 = true);
+                            }
+                            if ui.button("Select None").clicked() {
+                                self.candidates.iter_mut().for_each(|c| c.0 = false);
+                            }
+                            if ui.button("Add Selected").clicked() {
+                                let (selected, rest) = self.candidates.drain(..).partition(|c| c.0);
+                                self.candidates = rest;
+                                added = selected.into_iter().map(|c| c.1).collect();
+                            }
+                            ui.with_layout(egui::Layout::right_to_left(egui::Align::TOP), |ui| {
+                                if ui.button("❌").clicked() {
+                                    self.open = false;
+                                }
+                            });
+                        });
+                        ui.label(&self.status);
+                        if !self.skipped.is_empty() {
+                            ui.collapsing("Skipped", |ui| {
+                                for note in &self.skipped {
+                                    ui.weak(note);
+                                }
+                            });
+                        }
+                        egui::ScrollArea::vertical().show(ui, |ui| {
+                            for (selected, save) in &mut self.candidates {
+                                ui.checkbox(selected, format!("{}  —  {}", save.name, save.path));
+                            }
+                        });
+                    });
+
+                if ctx.input(|i| i.viewport().close_requested()) {
+                    self.open = false;
+                }
+            },
+        );
+        added
+    }
+}
@@ -0,0 +1,229 @@
+use crate::data::SaveUI;
+use regex::Regex;
+use std::{fs, path::Path};
+
+// Read from the save folder on top of the save's own patterns. It is synced like any
+// other file, so every device ends up with the same rules.
+pub const IGNORE_FILE: &str = ".rcignore";
+
+struct Rule {
+    regex: Regex,
+    negated: bool,
+    dir_only: bool,
+}
+
+impl Rule {
+    // Follows gitignore: `!` re-includes, a trailing `/` only matches folders, and a `/`
+    // anywhere else anchors the pattern to the save folder instead of any depth.
+    fn parse(line: &str) -> Option<Rule> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let (negated, pattern) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        let (dir_only, pattern) = match pattern.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, pattern),
+        };
+        let anchored = pattern.contains('/');
+        let chars: Vec<char> = pattern.trim_start_matches('/').chars().collect();
+        let mut re = String::from("^");
+        if !anchored {
+            re.push_str("(?:.*/)?");
+        }
+        let mut i = 0;
+        while i < chars.len() {
+            match chars[i] {
+                // `**/` matches any number of folders, any other `**` anything at all.
+                '*' if chars.get(i + 1) == Some(&'*') => {
+                    if chars.get(i + 2) == Some(&'/') {
+                        re.push_str("(?:.*/)?");
+                        i += 3;
+                    } else {
+                        re.push_str(".*");
+                        i += 2;
+                    }
+                    continue;
+                }
+                '*' => re.push_str("[^/]*"),
+                '?' => re.push_str("[^/]"),
+                '[' => match chars[i..].iter().position(|&c| c == ']') {
+                    Some(len) if len > 1 => {
+                        let class: String = chars[i + 1..i + len].iter().collect();
+                        let class = match class.strip_prefix('!') {
+                            Some(rest) => format!("^{}", rest),
+                            None => class,
+                        };
+                        re.push_str(&format!("[{}]", class.replace('\\', "\\\\")));
+                        i += len;
+                    }
+                    _ => re.push_str(&regex::escape("[")),
+                },
+                '\\' if i + 1 < chars.len() => {
+                    i += 1;
+                    re.push_str(&regex::escape(&chars[i].to_string()));
+                }
+                c => re.push_str(&regex::escape(&c.to_string())),
+            }
+            i += 1;
+        }
+        re.push('$');
+        Regex::new(&re).ok().map(|regex| Rule {
+            regex,
+            negated,
+            dir_only,
+        })
+    }
+}
+
+pub struct IgnoreRules {
+    rules: Vec<Rule>,
+    max_file_size: Option<u64>,
+}
+
+impl IgnoreRules {
+    pub fn for_save(save: &SaveUI, dirpath: &Path) -> Self {
+        let mut lines = save.ignore.clone();
+        if let Ok(text) = fs::read_to_string(dirpath.join(IGNORE_FILE)) {
+            lines.extend(text.lines().map(String::from));
+        }
+        Self {
+            rules: lines.iter().filter_map(|line| Rule::parse(line)).collect(),
+            max_file_size: (save.max_file_mb > 0).then(|| save.max_file_mb * 1024 * 1024),
+        }
+    }
+
+    // `path` is relative to the save folder and uses `/`. The last matching pattern wins.
+    pub fn is_ignored(&self, path: &str, is_dir: bool, size: u64) -> bool {
+        if !is_dir && self.max_file_size.is_some_and(|max| size > max) {
+            return true;
+        }
+        let mut ignored = false;
+        for rule in &self.rules {
+            if (is_dir || !rule.dir_only) && rule.regex.is_match(path) {
+                ignored = !rule.negated;
+            }
+        }
+        ignored
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_rules(lines: &[&str]) -> IgnoreRules {
+        IgnoreRules {
+            rules: lines.iter().filter_map(|line| Rule::parse(line)).collect(),
+            max_file_size: None,
+        }
+    }
+
+    fn ignores_file(rules: &IgnoreRules, path: &str) -> bool {
+        rules.is_ignored(path, false, 0)
+    }
+
+    #[test]
+    fn skips_blank_lines_and_comments() {
+        assert!(Rule::parse("").is_none());
+        assert!(Rule::parse("   ").is_none());
+        assert!(Rule::parse("# *.log").is_none());
+    }
+
+    #[test]
+    fn unanchored_patterns_match_at_any_depth() {
+        let rules = parse_rules(&["*.log"]);
+        assert!(ignores_file(&rules, "debug.log"));
+        assert!(ignores_file(&rules, "slot1/debug.log"));
+        assert!(!ignores_file(&rules, "debug.log.sav"));
+        assert!(!ignores_file(&rules, "debug.sav"));
+    }
+
+    #[test]
+    fn leading_slash_anchors_to_the_save_folder() {
+        let rules = parse_rules(&["/build"]);
+        assert!(ignores_file(&rules, "build"));
+        assert!(!ignores_file(&rules, "slot1/build"));
+    }
+
+    #[test]
+    fn trailing_slash_only_matches_folders() {
+        let rules = parse_rules(&["logs/"]);
+        assert!(rules.is_ignored("logs", true, 0));
+        assert!(rules.is_ignored("slot1/logs", true, 0));
+        assert!(!ignores_file(&rules, "logs"));
+    }
+
+    #[test]
+    fn double_star_matches_any_number_of_folders() {
+        let rules = parse_rules(&["a/**/b"]);
+        assert!(ignores_file(&rules, "a/b"));
+        assert!(ignores_file(&rules, "a/x/b"));
+        assert!(ignores_file(&rules, "a/x/y/b"));
+        assert!(!ignores_file(&rules, "c/a/b"));
+        assert!(!ignores_file(&rules, "a/bb"));
+    }
+
+    #[test]
+    fn trailing_double_star_matches_everything_inside() {
+        let rules = parse_rules(&["cache/**"]);
+        assert!(ignores_file(&rules, "cache/a"));
+        assert!(ignores_file(&rules, "cache/a/b.bin"));
+        assert!(!ignores_file(&rules, "cache"));
+        assert!(!ignores_file(&rules, "other/cache/a"));
+    }
+
+    #[test]
+    fn negation_reincludes_and_the_last_match_wins() {
+        let rules = parse_rules(&["*.sav", "!keep.sav"]);
+        assert!(ignores_file(&rules, "slot1.sav"));
+        assert!(!ignores_file(&rules, "keep.sav"));
+        assert!(!ignores_file(&rules, "slot1/keep.sav"));
+
+        let reversed = parse_rules(&["!keep.sav", "*.sav"]);
+        assert!(ignores_file(&reversed, "keep.sav"));
+    }
+
+    #[test]
+    fn bracket_classes_support_negation() {
+        let rules = parse_rules(&["slot[!0].sav"]);
+        assert!(ignores_file(&rules, "slot1.sav"));
+        assert!(!ignores_file(&rules, "slot0.sav"));
+
+        let rules = parse_rules(&["slot[0-2].sav"]);
+        assert!(ignores_file(&rules, "slot2.sav"));
+        assert!(!ignores_file(&rules, "slot3.sav"));
+    }
+
+    #[test]
+    fn backslash_escapes_special_characters() {
+        let rules = parse_rules(&["\\!important", "\\#notes", "a\\*b"]);
+        assert!(ignores_file(&rules, "!important"));
+        assert!(!ignores_file(&rules, "important"));
+        assert!(ignores_file(&rules, "#notes"));
+        assert!(ignores_file(&rules, "a*b"));
+        assert!(!ignores_file(&rules, "axb"));
+    }
+
+    #[test]
+    fn question_mark_matches_one_character() {
+        let rules = parse_rules(&["save?.dat"]);
+        assert!(ignores_file(&rules, "save1.dat"));
+        assert!(!ignores_file(&rules, "save10.dat"));
+        assert!(!ignores_file(&rules, "save/.dat"));
+    }
+
+    #[test]
+    fn size_limit_only_applies_to_files() {
+        let rules = IgnoreRules {
+            rules: Vec::new(),
+            max_file_size: Some(100),
+        };
+        assert!(rules.is_ignored("big.bin", false, 101));
+        assert!(!rules.is_ignored("small.bin", false, 100));
+        assert!(!rules.is_ignored("folder", true, 1000));
+    }
+}
@@ -0,0 +1,94 @@
+use crate::data;
+use std::{
+    error::Error,
+    fs,
+    path::PathBuf,
+    sync::{Mutex, MutexGuard},
+};
+
+const JOURNAL_FILE: &str = "transfers.json";
+
+// Several sync jobs can touch the journal at once, so every read-modify-write holds this.
+static LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum Direction {
+    Upload,
+    Download,
+}
+
+// A transfer that was started but hasn't finished yet. The local file lives in the
+// transfers folder, which isn't purged on exit, so it can be picked up after a restart.
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct Transfer {
+    pub save_id: String,
+    pub direction: Direction,
+    // File name inside the save's remote folder.
+    pub remote: String,
+    pub local: PathBuf,
+    pub size: u64,
+    // Modification time of the save version being transferred.
+    pub time: f64,
+}
+
+pub fn transfers_dir() -> Result<PathBuf, Box<dyn Error>> {
+    let path = data::config_path("transfers");
+    if !path.exists() {
+        fs::create_dir_all(&path)?;
+    }
+    Ok(path)
+}
+
+fn journal_path() -> PathBuf {
+    data::config_path(JOURNAL_FILE)
+}
+
+fn lock() -> MutexGuard<'static, ()> {
+    LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn read() -> Vec<Transfer> {
+    fs::read(journal_path())
+        .ok()
+        .and_then(|data| serde_json::from_slice(&data).ok())
+        .unwrap_or_default()
+}
+
+fn write(transfers: &[Transfer]) -> Result<(), Box<dyn Error>> {
+    fs::write(journal_path(), serde_json::to_vec(transfers)?)?;
+    Ok(())
+}
+
+pub fn load() -> Vec<Transfer> {
+    let _guard = lock();
+    read()
+}
+
+pub fn find(save_id: &str, direction: Direction) -> Option<Transfer> {
+    load()
+        .into_iter()
+        .find(|t| t.save_id == save_id && t.direction == direction)
+}
+
+// Adds a transfer, replacing any earlier one for the same save and direction.
+pub fn record(transfer: Transfer) -> Result<(), Box<dyn Error>> {
+    let _guard = lock();
+    let mut transfers = read();
+    transfers.retain(|t| !(t.save_id == transfer.save_id && t.direction == transfer.direction));
+    transfers.push(transfer);
+    write(&transfers)
+}
+
+// Forgets a transfer and removes its local file.
+pub fn finish(save_id: &str, direction: Direction) -> Result<(), Box<dyn Error>> {
+    let _guard = lock();
+    let mut transfers = read();
+    transfers.retain(|t| {
+        let done = t.save_id == save_id && t.direction == direction;
+        if done {
+            let _ = fs::remove_file(&t.local);
+        }
+        !done
+    });
+    write(&transfers)
+}
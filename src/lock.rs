@@ -0,0 +1,218 @@
+use crate::{
+    data::Device,
+    worker::{Event, EventSender},
+};
+use chrono::{Local, TimeZone};
+use ftp::FtpStream;
+use std::{
+    error::Error,
+    fmt,
+    io::Cursor,
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+// Creating a directory is atomic on every FTP server, so the directory is the lock and
+// the file inside it only says who holds it.
+pub const LOCK_DIR: &str = ".lock";
+const OWNER_FILE: &str = ".lock/owner.json";
+// A lock that hasn't been refreshed for this long belongs to a sync that died.
+const LOCK_TTL: i64 = 30 * 60;
+// The slowest rate `extend` allows for, so a big save on a slow link still finishes before
+// its lock looks stale. A lock left by a crash lasts longer in exchange.
+const MIN_RATE: u64 = 512 * 1024;
+// The lock on the files shared by every save is only held for a moment, so it expires
+// sooner and a busy one is waited for instead of failing the sync.
+const SHARED_TTL: i64 = 2 * 60;
+const SHARED_WAIT: Duration = Duration::from_secs(30);
+// How long a lock may go without its owner's name before it counts as left by a crash.
+const UNNAMED_GRACE: Duration = Duration::from_secs(30);
+
+// A device can't tell its own jobs apart by the lock file, so they take turns here first.
+static SHARED: Mutex<()> = Mutex::new(());
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct LockOwner {
+    pub device_id: String,
+    pub device_name: String,
+    pub acquired: i64,
+    pub expires: i64,
+}
+
+// Returned when another device is syncing the same save.
+#[derive(Debug)]
+pub struct Locked {
+    pub device_name: String,
+    pub expires: i64,
+}
+
+impl fmt::Display for Locked {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match Local.timestamp_opt(self.expires, 0).single() {
+            Some(expires) => write!(
+                f,
+                "Locked by {} until {}",
+                self.device_name,
+                expires.format("%H:%M")
+            ),
+            None => write!(f, "Locked by {}", self.device_name),
+        }
+    }
+}
+
+impl Error for Locked {}
+
+impl From<LockOwner> for Locked {
+    fn from(owner: LockOwner) -> Self {
+        Self {
+            device_name: owner.device_name,
+            expires: owner.expires,
+        }
+    }
+}
+
+fn read_owner(ftp_stream: &mut FtpStream) -> Option<LockOwner> {
+    let cursor = ftp_stream.simple_retr(OWNER_FILE).ok()?;
+    serde_json::from_slice(&cursor.into_inner()).ok()
+}
+
+fn write_owner(
+    ftp_stream: &mut FtpStream,
+    device: &Device,
+    ttl: i64,
+) -> Result<(), Box<dyn Error>> {
+    let now = Local::now().timestamp();
+    let owner = LockOwner {
+        device_id: device.id.clone(),
+        device_name: device.name.clone(),
+        acquired: now,
+        expires: now + ttl,
+    };
+    ftp_stream.put(OWNER_FILE, &mut Cursor::new(serde_json::to_vec(&owner)?))?;
+    Ok(())
+}
+
+// Creates the lock directory, the only atomic step FTP offers. An expired lock, or one
+// still unnamed after the grace period, is removed and created again the same way; writing
+// over it and reading it back would let two devices both pass. A live lock is waited for
+// until `wait` runs out. With `reuse_own`, a lock this device left behind is taken over.
+fn take(
+    channel: &EventSender,
+    ftp_stream: &mut FtpStream,
+    device: &Device,
+    ttl: i64,
+    wait: Duration,
+    reuse_own: bool,
+) -> Result<(), Box<dyn Error>> {
+    let started = Instant::now();
+    loop {
+        if ftp_stream.mkdir(LOCK_DIR).is_ok() {
+            return write_owner(ftp_stream, device, ttl);
+        }
+        match read_owner(ftp_stream) {
+            Some(owner) if reuse_own && owner.device_id == device.id => {
+                return write_owner(ftp_stream, device, ttl);
+            }
+            Some(owner) if owner.expires <= Local::now().timestamp() => {
+                remove_stale(ftp_stream, &owner)?;
+                channel.send(Event::Log(format!(
+                    "Removed a stale lock from {}",
+                    owner.device_name
+                )))?;
+                continue;
+            }
+            Some(owner) if started.elapsed() >= wait => return Err(Locked::from(owner).into()),
+            // The owner writes its name right after creating the lock, so an unnamed one
+            // is only taken for a crash's once it stays that way.
+            None if started.elapsed() >= UNNAMED_GRACE => {
+                // Fails if the name turned up after all, which the next pass sees.
+                if ftp_stream.rmdir(LOCK_DIR).is_err() && read_owner(ftp_stream).is_none() {
+                    return Err("Couldn't remove an abandoned sync lock".into());
+                }
+                continue;
+            }
+            // Busy, or not named yet.
+            _ => {}
+        }
+        channel.check_cancelled()?;
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
+// Removes an expired lock, unless another device replaced it since it was read.
+fn remove_stale(ftp_stream: &mut FtpStream, stale: &LockOwner) -> Result<(), Box<dyn Error>> {
+    let same =
+        |owner: &LockOwner| owner.device_id == stale.device_id && owner.acquired == stale.acquired;
+    if !read_owner(ftp_stream).is_some_and(|owner| same(&owner)) {
+        return Ok(());
+    }
+    let removed = ftp_stream
+        .rm(OWNER_FILE)
+        .and_then(|_| ftp_stream.rmdir(LOCK_DIR));
+    if removed.is_err() && read_owner(ftp_stream).is_some_and(|owner| same(&owner)) {
+        return Err("Couldn't remove a stale sync lock".into());
+    }
+    Ok(())
+}
+
+// Takes the lock on the current remote folder. A lock this device left behind is taken
+// over, one another device holds fails the sync.
+pub fn acquire(
+    channel: &EventSender,
+    ftp_stream: &mut FtpStream,
+    device: &Device,
+) -> Result<(), Box<dyn Error>> {
+    take(channel, ftp_stream, device, LOCK_TTL, Duration::ZERO, true)
+}
+
+// Runs `f` holding the lock on the current remote folder, for the files every save shares.
+// Unlike `acquire`, a lock held by this device isn't taken over, and a busy lock is waited
+// for.
+pub fn with_shared<T>(
+    channel: &EventSender,
+    ftp_stream: &mut FtpStream,
+    device: &Device,
+    f: impl FnOnce(&mut FtpStream) -> Result<T, Box<dyn Error>>,
+) -> Result<T, Box<dyn Error>> {
+    let _guard = SHARED
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    take(channel, ftp_stream, device, SHARED_TTL, SHARED_WAIT, false)?;
+    let result = f(ftp_stream);
+    let _ = release(ftp_stream, device);
+    result
+}
+
+// Moves the expiry of a lock this device still holds. One that went stale and was taken
+// over by another device stays theirs, and the sync stops.
+fn renew(ftp_stream: &mut FtpStream, device: &Device, ttl: i64) -> Result<(), Box<dyn Error>> {
+    match read_owner(ftp_stream) {
+        Some(owner) if owner.device_id == device.id => write_owner(ftp_stream, device, ttl),
+        Some(owner) => Err(Locked::from(owner).into()),
+        None => Err("Lost the sync lock".into()),
+    }
+}
+
+// Pushes the expiry back, for syncs that run longer than the lock lasts.
+pub fn refresh(ftp_stream: &mut FtpStream, device: &Device) -> Result<(), Box<dyn Error>> {
+    renew(ftp_stream, device, LOCK_TTL)
+}
+
+// Pushes the expiry back far enough to get through `bytes` of work. The control connection
+// is busy during a transfer, so the lock can't be refreshed while it runs.
+pub fn extend(
+    ftp_stream: &mut FtpStream,
+    device: &Device,
+    bytes: u64,
+) -> Result<(), Box<dyn Error>> {
+    renew(ftp_stream, device, LOCK_TTL + (bytes / MIN_RATE) as i64)
+}
+
+pub fn release(ftp_stream: &mut FtpStream, device: &Device) -> Result<(), Box<dyn Error>> {
+    if read_owner(ftp_stream).is_some_and(|owner| owner.device_id == device.id) {
+        ftp_stream.rm(OWNER_FILE)?;
+        ftp_stream.rmdir(LOCK_DIR)?;
+    }
+    Ok(())
+}
@@ -1,97 +1,43 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 #![allow(rustdoc::missing_crate_level_docs, unused_variables)]
 
+pub mod cli;
 pub mod data;
+pub mod discover;
+pub mod ignore;
+pub mod journal;
+pub mod lock;
+pub mod manifest;
+pub mod paths;
+pub mod progress;
+pub mod restore;
+pub mod retry;
+pub mod scheduler;
 pub mod settings;
+pub mod state;
+pub mod steam;
 pub mod sync;
+pub mod worker;
 
-use core::panic;
 use eframe::egui;
 use egui::Pos2;
+use journal::Direction;
+use scheduler::Priority;
 use std::{
-    sync::mpsc::{self, Receiver, Sender},
-    thread::{self, JoinHandle},
+    collections::HashMap,
+    time::{Duration, Instant},
 };
 
 const SCALE: f32 = 1.5;
 
-struct ThreadData {
-    join_handle: JoinHandle<()>,
-    sender: Sender<String>,
-    receiver: Receiver<String>,
-}
-
 fn main() -> eframe::Result {
-    let available_threads: usize = thread::available_parallelism().unwrap().into();
-    let mut threads: Vec<ThreadData> = Vec::new();
-
-    for id in 0..available_threads {
-        let (send_to_main, recv_from_thread): (Sender<String>, Receiver<String>) = mpsc::channel();
-        let (send_to_thread, recv_from_main): (Sender<String>, Receiver<String>) = mpsc::channel();
-
-        let handle = thread::Builder::new()
-            .name(format!("Worker thread {id}").to_string())
-            .spawn(move || {
-                let mut running = true;
-                while running {
-                    send_to_main
-                        .send("free".to_string())
-                        .expect("Sending 'free' message to main");
-                    let result = recv_from_main.recv();
-                    let data: String;
-                    match result {
-                        Ok(text) => data = text,
-                        Err(err) => {
-                            continue;
-                        }
-                    }
-                    let mut data_iter = data.split(";");
-                    let command = data_iter.next().expect("Getting command from message");
-                    match command {
-                        "sync" => {
-                            let save_num: usize = data_iter
-                                .next()
-                                .expect("Getting save number")
-                                .parse()
-                                .expect("Casting to usize");
-                            let data = data::load_config_data();
-                            let result = sync::sync_save_ftp(
-                                &send_to_main,
-                                save_num,
-                                &data.saves[save_num].name,
-                                &data.saves[save_num].path,
-                                &data.ftp_config.ip,
-                                &data.ftp_config.user,
-                                &data.ftp_config.passwd,
-                                data.ftp_config.port,
-                            );
-                            match result {
-                                Ok(_) => (),
-                                Err(err) => println!("{}", err),
-                            }
-                            send_to_main
-                                .send("done".to_string())
-                                .expect("Failed to send 'done' message to main");
-                        }
-                        "join" => {
-                            println!("Joining thread {}", id);
-                            running = false
-                        }
-                        _ => panic!("Invalid command sent to thread"),
-                    }
-                }
-            })
-            .unwrap();
-        let thread = ThreadData {
-            join_handle: handle,
-            sender: send_to_thread,
-            receiver: recv_from_thread,
-        };
-        threads.push(thread);
-    }
     data::check_config_folder();
     data::load_config_data();
     env_logger::init();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("--dry-run") {
+        std::process::exit(cli::dry_run(&args[1..]));
+    }
     let options = eframe::NativeOptions {
         viewport: egui::ViewportBuilder::default()
             .with_inner_size([1280.0, 720.0])
@@ -101,10 +47,7 @@ fn main() -> eframe::Result {
             .with_transparent(true),
         ..Default::default()
     };
-    let app = MyApp {
-        threads,
-        ..Default::default()
-    };
+    let app = MyApp::default();
     let result = eframe::run_native(
         "raincloud",
         options,
@@ -119,13 +62,76 @@ fn main() -> eframe::Result {
     result
 }
 
+#[derive(Clone)]
+struct TransferState {
+    stage: String,
+    done: u64,
+    total: u64,
+    started: Instant,
+}
+
+impl TransferState {
+    fn draw(&self, ui: &mut egui::Ui) {
+        let fraction = self.done as f32 / self.total.max(1) as f32;
+        ui.add(
+            egui::ProgressBar::new(fraction)
+                .desired_width(160.0)
+                .text(format!(
+                    "{} / {}",
+                    progress::format_bytes(self.done),
+                    progress::format_bytes(self.total)
+                )),
+        );
+        let elapsed = self.started.elapsed().as_secs_f64();
+        if elapsed > 0.5 && self.done > 0 {
+            let rate = self.done as f64 / elapsed;
+            let eta = (self.total.saturating_sub(self.done)) as f64 / rate;
+            ui.label(format!(
+                "{}/s, {} left",
+                progress::format_bytes(rate as u64),
+                progress::format_duration(Duration::from_secs_f64(eta))
+            ));
+        }
+    }
+}
+
+#[derive(Clone)]
+struct LastUpload {
+    device: String,
+    uploaded: i64,
+    version: u64,
+}
+
+impl LastUpload {
+    fn draw(&self, ui: &mut egui::Ui) {
+        let age = chrono::Local::now().timestamp() - self.uploaded;
+        ui.weak(format!(
+            "Last uploaded from {}, {}",
+            self.device,
+            progress::format_age(age)
+        ))
+        .on_hover_text(format!("Version {}", self.version));
+    }
+}
+
 struct SaveInfo {
     to_delete: bool,
     editing: bool,
     sync_info: String,
     sync_request: bool,
+    cancel_request: bool,
+    revert_request: bool,
+    resolve_request: Option<Direction>,
+    preview_request: bool,
+    link_request: Option<bool>,
     syncing: bool,
-    thread: usize,
+    log: Vec<String>,
+    transfer: Option<TransferState>,
+    last_upload: Option<LastUpload>,
+    conflict: Option<String>,
+    plan: Option<sync::SyncPlan>,
+    // The last sync found another device's cloud save with the same name.
+    same_name: bool,
 }
 impl Default for SaveInfo {
     fn default() -> Self {
@@ -134,26 +140,146 @@ impl Default for SaveInfo {
             editing: false,
             sync_info: "".to_string(),
             sync_request: false,
+            cancel_request: false,
+            revert_request: false,
+            resolve_request: None,
+            preview_request: false,
+            link_request: None,
             syncing: false,
-            thread: 0,
+            log: Vec::new(),
+            transfer: None,
+            last_upload: None,
+            conflict: None,
+            plan: None,
+            same_name: false,
         }
     }
 }
 impl Clone for SaveInfo {
     fn clone(&self) -> Self {
         Self {
-            thread: self.thread,
             to_delete: self.to_delete,
             editing: self.editing,
             sync_info: self.sync_info.clone(),
             sync_request: self.sync_request,
+            cancel_request: self.cancel_request,
+            revert_request: self.revert_request,
+            resolve_request: self.resolve_request,
+            preview_request: self.preview_request,
+            link_request: self.link_request,
             syncing: self.syncing,
+            log: self.log.clone(),
+            transfer: self.transfer.clone(),
+            last_upload: self.last_upload.clone(),
+            conflict: self.conflict.clone(),
+            plan: self.plan.clone(),
+            same_name: self.same_name,
+        }
+    }
+}
+
+impl SaveInfo {
+    fn apply(&mut self, event: worker::Event) {
+        match event {
+            worker::Event::Started => {
+                self.sync_info = "Starting sync".to_string();
+                self.log.clear();
+                self.conflict = None;
+                self.plan = None;
+                self.same_name = false;
+            }
+            worker::Event::Progress { stage, done, total } => {
+                self.transfer = if total == 0 {
+                    None
+                } else {
+                    let started = match &self.transfer {
+                        Some(t) if t.stage == stage => t.started,
+                        _ => Instant::now(),
+                    };
+                    Some(TransferState {
+                        stage: stage.clone(),
+                        done,
+                        total,
+                        started,
+                    })
+                };
+                self.sync_info = stage;
+            }
+            worker::Event::Log(text) => self.log.push(text),
+            worker::Event::LastUpload {
+                device,
+                uploaded,
+                version,
+            } => {
+                self.last_upload = Some(LastUpload {
+                    device,
+                    uploaded,
+                    version,
+                });
+            }
+            worker::Event::Conflict(text) => {
+                self.sync_info = format!("Conflict: {}", text);
+                self.conflict = Some(text);
+            }
+            worker::Event::Plan(plan) => self.plan = Some(*plan),
+            worker::Event::Finished(result) => {
+                self.same_name = matches!(result, Ok(worker::SyncOutcome::SameName));
+                self.sync_info = match result {
+                    Ok(outcome) => outcome.describe(),
+                    Err(err) => format!("Sync failed: {}", err),
+                };
+                self.transfer = None;
+            }
+        }
+    }
+}
+
+// A path box with a folder picker, and a checkbox to give this device its own path.
+fn edit_path(
+    ui: &mut egui::Ui,
+    path: &mut String,
+    device_paths: &mut HashMap<String, String>,
+    device: &data::Device,
+) {
+    let mut overridden = device_paths.contains_key(&device.id);
+    if ui
+        .checkbox(&mut overridden, "")
+        .on_hover_text(format!("Use a different folder on {}", device.name))
+        .changed()
+    {
+        if overridden {
+            device_paths.insert(device.id.clone(), path.clone());
+        } else {
+            device_paths.remove(&device.id);
+        }
+    }
+    let path = match device_paths.get_mut(&device.id) {
+        Some(path) => path,
+        None => path,
+    };
+    ui.vertical(|ui| {
+        ui.text_edit_singleline(path)
+            .on_hover_text("Variables: {home} {documents} {appdata} {xdg_data} {steam}");
+        if path.contains('{') {
+            ui.label(egui::RichText::new(paths::expand(path)).small().weak());
+        }
+    });
+    if ui.button("Folder").clicked() {
+        let result = rfd::FileDialog::new().set_directory("~").pick_folder();
+        if result != None {
+            let result = result.unwrap().to_str().unwrap().to_string();
+            *path = paths::contract(&result);
         }
     }
 }
 
 impl data::SaveUI {
-    fn display(&mut self, ui: &mut egui::Ui, data: &mut SaveInfo) -> SaveInfo {
+    fn display(
+        &mut self,
+        ui: &mut egui::Ui,
+        data: &mut SaveInfo,
+        device: &data::Device,
+    ) -> SaveInfo {
         ui.horizontal(|ui| {
             if data.editing {
                 ui.add_sized([80.0, 20.0], egui::TextEdit::singleline(&mut self.name));
@@ -169,53 +295,381 @@ impl data::SaveUI {
                     data.editing = true;
                 }
             }
-            ui.text_edit_singleline(&mut self.path);
-            if ui.button("Folder").clicked() {
-                let result = rfd::FileDialog::new().set_directory("~").pick_folder();
-                if result != None {
-                    let result = result.unwrap().to_str().unwrap().to_string();
-                    self.path = result;
+            edit_path(ui, &mut self.path, &mut self.device_paths, device);
+            if data.syncing {
+                if ui.button("Cancel").clicked() {
+                    data.cancel_request = true;
+                }
+            } else {
+                if ui.button("Sync").clicked() {
+                    data.sync_request = true;
+                }
+                if ui
+                    .button("Preview")
+                    .on_hover_text("Show what a sync would do, without transferring anything")
+                    .clicked()
+                {
+                    data.preview_request = true;
+                }
+            }
+            if let Some(conflict) = data.conflict.clone().filter(|_| !data.syncing) {
+                if self.mode.allows(Direction::Upload)
+                    && ui
+                        .button("Keep local")
+                        .on_hover_text(format!(
+                            "Conflict: {}.\nUpload this device's copy.",
+                            conflict
+                        ))
+                        .clicked()
+                {
+                    data.resolve_request = Some(Direction::Upload);
+                }
+                if self.mode.allows(Direction::Download)
+                    && ui
+                        .button("Keep cloud")
+                        .on_hover_text(format!("Conflict: {}.\nDownload the cloud copy.", conflict))
+                        .clicked()
+                {
+                    data.resolve_request = Some(Direction::Download);
+                }
+            }
+            if data.same_name && !data.syncing {
+                if ui
+                    .button("Link")
+                    .on_hover_text(format!(
+                        "Another device has a cloud save named '{}'.\nSync this save with it.",
+                        self.name
+                    ))
+                    .clicked()
+                {
+                    data.link_request = Some(true);
+                }
+                if ui
+                    .button("Keep separate")
+                    .on_hover_text("Give this save a cloud folder of its own.")
+                    .clicked()
+                {
+                    data.link_request = Some(false);
                 }
             }
-            if ui.button("Sync").clicked() {
-                data.sync_request = true;
+            if ui
+                .add_enabled(!data.syncing, egui::Button::new("Revert"))
+                .on_hover_text("Revert last restore")
+                .clicked()
+            {
+                data.revert_request = true;
             }
             if ui.button("Delete").clicked() {
                 data.to_delete = true;
             }
-            ui.label(&data.sync_info);
+            let status = ui.label(&data.sync_info);
+            if !data.log.is_empty() {
+                status.on_hover_text(data.log.join("\n"));
+            }
+            if let Some(transfer) = &data.transfer {
+                transfer.draw(ui);
+            } else if let Some(last_upload) = &data.last_upload {
+                last_upload.draw(ui);
+            }
         });
+        if let Some(plan) = data.plan.clone().filter(|_| !data.syncing) {
+            ui.indent(("plan", &self.id), |ui| {
+                ui.label(plan.describe());
+                let lists = [
+                    ("Add", &plan.added),
+                    ("Change", &plan.changed),
+                    ("Delete", &plan.deleted),
+                ];
+                for (heading, files) in lists.into_iter().filter(|(_, f)| !f.is_empty()) {
+                    ui.collapsing(format!("{} ({})", heading, files.len()), |ui| {
+                        for file in files {
+                            ui.weak(file);
+                        }
+                    });
+                }
+                ui.horizontal(|ui| {
+                    let transfers = matches!(
+                        plan.action,
+                        sync::PlanAction::Upload | sync::PlanAction::Download
+                    );
+                    if transfers && ui.button("Sync now").clicked() {
+                        data.sync_request = true;
+                    }
+                    if ui.button("Dismiss").clicked() {
+                        data.plan = None;
+                    }
+                });
+            });
+        }
+        if data.editing {
+            let checked = self.extra_roots(&device.id);
+            ui.indent(("ignore", &self.id), |ui| {
+                egui::ComboBox::from_label("Direction")
+                    .selected_text(self.mode.label())
+                    .show_ui(ui, |ui| {
+                        for mode in data::SyncMode::ALL {
+                            ui.selectable_value(&mut self.mode, mode, mode.label());
+                        }
+                    });
+                ui.label(format!(
+                    "Ignore patterns, one per line (! to include). {} in the folder adds more.",
+                    ignore::IGNORE_FILE
+                ));
+                let mut text = self.ignore.join("\n");
+                if ui
+                    .add(egui::TextEdit::multiline(&mut text).desired_rows(3))
+                    .changed()
+                {
+                    self.ignore = if text.is_empty() {
+                        Vec::new()
+                    } else {
+                        text.split('\n').map(String::from).collect()
+                    };
+                }
+                ui.label("Extra folders, archived and restored together with the main one");
+                let mut remove = None;
+                for (i, root) in self.roots.iter_mut().enumerate() {
+                    ui.horizontal(|ui| {
+                        ui.add_sized([80.0, 20.0], egui::TextEdit::singleline(&mut root.name))
+                            .on_hover_text(
+                                "Names the folder in the cloud, keep it the same on every device",
+                            );
+                        edit_path(ui, &mut root.path, &mut root.device_paths, device);
+                        if ui.button("Remove").clicked() {
+                            remove = Some(i);
+                        }
+                    });
+                    if let Err(problem) = &checked[i] {
+                        ui.weak(*problem);
+                    }
+                }
+                if let Some(i) = remove {
+                    self.roots.remove(i);
+                }
+                if ui.button("Add folder").clicked() {
+                    self.roots.push(data::SaveRoot {
+                        name: format!("folder{}", self.roots.len() + 1),
+                        path: String::new(),
+                        device_paths: HashMap::new(),
+                    });
+                }
+                ui.horizontal(|ui| {
+                    ui.label("Skip files larger than");
+                    ui.add(egui::DragValue::new(&mut self.max_file_mb).suffix(" MB"));
+                    ui.weak("0 for no limit");
+                });
+            });
+        }
         data.clone()
     }
 }
 
 struct MyApp {
+    device: data::Device,
     server: String,
     ftp: data::FtpDetails,
     saves: Vec<data::SaveUI>,
     save_info: Vec<SaveInfo>,
-    sync_queue: Vec<usize>,
+    scheduler: scheduler::Scheduler,
+    show_queue: bool,
     settings_window: settings::SettingsWindow,
-    threads: Vec<ThreadData>,
+    discover_window: discover::DiscoverWindow,
 }
 
 impl Default for MyApp {
     fn default() -> Self {
         let data = data::load_config_data();
-        let (tx, rx): (Sender<String>, Receiver<String>) = mpsc::channel();
         let mut save_info = Vec::new();
         for save in &data.saves {
             save_info.push(SaveInfo::default());
         }
-        Self {
+        let mut app = Self {
+            device: data.device,
             server: data.server,
             ftp: data.ftp_config,
             saves: data.saves.clone(),
             save_info,
-            sync_queue: Vec::new(),
+            scheduler: scheduler::Scheduler::new(data.scheduler),
+            show_queue: false,
             settings_window: settings::SettingsWindow::default(),
-            threads: Vec::new(),
+            discover_window: discover::DiscoverWindow::default(),
+        };
+        // Pick up transfers that were still in flight when the app last closed.
+        for transfer in journal::load() {
+            if let Some(save_num) = app.saves.iter().position(|s| s.id == transfer.save_id) {
+                app.queue_sync(save_num, Priority::Normal);
+            }
         }
+        app
+    }
+}
+
+impl MyApp {
+    fn queue_sync(&mut self, save_num: usize, priority: Priority) {
+        self.queue_job(save_num, priority, None, false, None);
+    }
+
+    // `force` skips change detection and copies in that direction, to settle a conflict.
+    // A `dry_run` only works out the plan. `link` answers whether to share another device's
+    // cloud save with the same name.
+    fn queue_job(
+        &mut self,
+        save_num: usize,
+        priority: Priority,
+        force: Option<Direction>,
+        dry_run: bool,
+        link: Option<bool>,
+    ) {
+        let save = &self.saves[save_num];
+        let job = worker::SyncJob {
+            save: save.clone(),
+            ftp: self.ftp.clone(),
+            device: self.device.clone(),
+            max_retries: self.scheduler.config.max_retries,
+            force,
+            dry_run,
+            link: save.name_link(&self.saves).or(link),
+        };
+        // Syncing only talks to FTP for now, whichever server is selected.
+        if self.scheduler.enqueue(job, "ftp", priority) {
+            self.save_info[save_num].syncing = true;
+            self.save_info[save_num].sync_info = "Queued".to_string();
+        }
+    }
+
+    // Drops a queued sync, or asks a running one to stop at its next checkpoint.
+    fn cancel_sync(&mut self, save_num: usize) {
+        let info = &mut self.save_info[save_num];
+        if self.scheduler.cancel(&self.saves[save_num].id) {
+            info.syncing = false;
+            info.sync_info = "Sync cancelled".to_string();
+        } else if info.syncing {
+            info.sync_info = "Cancelling...".to_string();
+        }
+    }
+
+    fn revert_restore(&mut self, save_num: usize) {
+        let save = &self.saves[save_num];
+        self.save_info[save_num].sync_info =
+            match restore::revert_last_restore(save, &self.device.id) {
+                // The next sync reports a conflict, so the user picks which copy to keep.
+                Ok(()) => "Reverted last restore".to_string(),
+                Err(err) => err.to_string(),
+            };
+    }
+
+    fn draw_queue(&mut self, ui: &mut egui::Ui) {
+        let mut retry = Vec::new();
+        egui::ScrollArea::vertical().show(ui, |ui| {
+            ui.heading("Running");
+            if self.scheduler.running().is_empty() {
+                ui.weak("Nothing running");
+            }
+            let mut cancel = None;
+            for job in self.scheduler.running() {
+                ui.horizontal(|ui| {
+                    ui.label(&job.name);
+                    ui.weak(format!(
+                        "{}, {}",
+                        job.started_at.format("%H:%M:%S"),
+                        progress::format_duration(job.elapsed())
+                    ));
+                    if job.is_cancelling() {
+                        ui.weak("cancelling");
+                    } else if ui.small_button("✖").on_hover_text("Cancel").clicked() {
+                        cancel = Some(job.id);
+                    }
+                });
+            }
+            if let Some(job_id) = cancel {
+                self.scheduler.cancel_running(job_id);
+            }
+
+            ui.separator();
+            ui.heading("Queued");
+            if self.scheduler.queued().is_empty() {
+                ui.weak("Queue is empty");
+            }
+            let mut move_job = None;
+            let mut remove_job = None;
+            for job in self.scheduler.queued() {
+                ui.horizontal(|ui| {
+                    if ui.small_button("⏶").clicked() {
+                        move_job = Some((job.id, true));
+                    }
+                    if ui.small_button("⏷").clicked() {
+                        move_job = Some((job.id, false));
+                    }
+                    ui.label(&job.name);
+                    ui.weak(job.priority.label());
+                    if ui.small_button("✖").on_hover_text("Remove").clicked() {
+                        remove_job = Some(job.save_id.clone());
+                    }
+                });
+            }
+            if let Some((job_id, up)) = move_job {
+                self.scheduler.move_queued(job_id, up);
+            }
+            // Goes through cancelling, so the save's row stops saying it is queued.
+            if let Some(save_id) = remove_job {
+                if let Some(save_num) = self.saves.iter().position(|s| s.id == save_id) {
+                    self.cancel_sync(save_num);
+                }
+            }
+
+            ui.separator();
+            ui.horizontal(|ui| {
+                ui.heading("Finished");
+                if !self.scheduler.history().is_empty() && ui.small_button("Clear").clicked() {
+                    self.scheduler.clear_history();
+                }
+            });
+            for job in self.scheduler.history() {
+                ui.horizontal(|ui| {
+                    match &job.result {
+                        Ok(_) => ui.label("✔"),
+                        Err(_) => ui.colored_label(ui.visuals().error_fg_color, "✖"),
+                    };
+                    ui.label(&job.name);
+                    if job.dry_run {
+                        ui.weak("preview");
+                    }
+                    ui.weak(format!(
+                        "{}, took {}",
+                        job.started_at.format("%H:%M:%S"),
+                        progress::format_duration(job.duration)
+                    ));
+                    let failed = match &job.result {
+                        Ok(outcome) => matches!(
+                            outcome,
+                            worker::SyncOutcome::Cancelled | worker::SyncOutcome::Locked(_)
+                        ),
+                        Err(_) => true,
+                    };
+                    if failed && ui.small_button("Retry").clicked() {
+                        retry.push((job.save_id.clone(), job.force, job.dry_run));
+                    }
+                });
+                if let Err(err) = &job.result {
+                    ui.colored_label(ui.visuals().error_fg_color, err);
+                }
+            }
+        });
+        for (save_id, force, dry_run) in retry {
+            if let Some(save_num) = self.saves.iter().position(|s| s.id == save_id) {
+                self.queue_job(save_num, Priority::High, force, dry_run, None);
+            }
+        }
+    }
+
+    fn save_config(&self) {
+        let _ = data::save_config_data(
+            &self.device,
+            self.server.clone(),
+            &self.ftp,
+            &self.saves,
+            &self.scheduler.config,
+        );
     }
 }
 
@@ -224,6 +678,11 @@ impl eframe::App for MyApp {
         egui::Rgba::TRANSPARENT.to_array() // Make sure we don't paint anything behind the rounded corners
     }
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        for (save_id, event) in self.scheduler.poll() {
+            if let Some(save_num) = self.saves.iter().position(|s| s.id == save_id) {
+                self.save_info[save_num].apply(event);
+            }
+        }
         let panel_frame = egui::Frame {
             fill: ctx.style().visuals.window_fill(),
             rounding: 5.0.into(),
@@ -249,19 +708,31 @@ impl eframe::App for MyApp {
                 egui::menu::bar(ui, |ui| {
                     ui.menu_button("Saves", |ui| {
                         if ui.button("New").clicked() {
-                            let s = data::SaveUI {
-                                name: "".to_string(),
-                                path: "".to_string(),
-                            };
+                            let s = data::SaveUI::new("".to_string(), "".to_string());
                             let info = SaveInfo::default();
                             self.saves.push(s);
                             self.save_info.push(info);
                         }
+                        if ui.button("Discover from manifest").clicked() {
+                            let result = rfd::FileDialog::new()
+                                .add_filter("Ludusavi manifest", &["yaml", "yml"])
+                                .set_directory("~")
+                                .pick_file();
+                            if let Some(path) = result {
+                                self.discover_window.start_manifest_scan(path);
+                            }
+                        }
+                        if ui.button("Discover from Steam").clicked() {
+                            self.discover_window.start_steam_scan();
+                        }
                         if ui.button("Sync All").clicked() {
-                            let mut n = 0;
-                            for _ in &self.saves {
-                                self.sync_queue.push(n);
-                                n += 1;
+                            for save_num in 0..self.saves.len() {
+                                self.queue_sync(save_num, Priority::Normal);
+                            }
+                        }
+                        if ui.button("Cancel All").clicked() {
+                            for save_num in 0..self.save_info.len() {
+                                self.cancel_sync(save_num);
                             }
                         }
                     });
@@ -279,75 +750,93 @@ impl eframe::App for MyApp {
                             self.settings_window.open = true;
                         }
                     });
+                    ui.toggle_value(&mut self.show_queue, "Queue");
                     ui.with_layout(egui::Layout::right_to_left(egui::Align::TOP), |ui| {
                         if ui.button("❌").clicked() {
                             ui.ctx().send_viewport_cmd(egui::ViewportCommand::Close);
                         }
                     });
                 });
+                if self.show_queue {
+                    egui::SidePanel::right("queue_panel")
+                        .resizable(false)
+                        .exact_width(320.0)
+                        .show_inside(ui, |ui| self.draw_queue(ui));
+                }
                 let mut to_remove = Vec::new();
+                let mut cancel_requests = Vec::new();
+                let mut sync_requests = Vec::new();
+                let mut revert_requests = Vec::new();
+                let mut resolve_requests = Vec::new();
+                let mut preview_requests = Vec::new();
+                let mut link_requests = Vec::new();
                 let mut save_num: usize = 0;
                 if self.saves.len() == 0 {
                     ui.label("No saves to show");
                 }
                 for mut save in &mut self.saves {
-                    if self.save_info[save_num].syncing {
-                        let result = self.threads[self.save_info[save_num].thread]
-                            .receiver
-                            .try_recv();
-                        match result {
-                            Ok(text) => {
-                                println!("{}", text);
-                                if text == "done".to_string() {
-                                    self.save_info[save_num].syncing = false;
-                                } else {
-                                    self.save_info[save_num].sync_info = text;
-                                }
-                            }
-                            Err(err) => (),
-                        }
-                    }
-                    self.save_info[save_num] =
-                        data::SaveUI::display(&mut save, ui, &mut self.save_info[save_num]);
+                    self.save_info[save_num].syncing = self.scheduler.is_active(&save.id);
+                    self.save_info[save_num] = data::SaveUI::display(
+                        &mut save,
+                        ui,
+                        &mut self.save_info[save_num],
+                        &self.device,
+                    );
                     if self.save_info[save_num].to_delete {
                         to_remove.push(save_num);
                     }
-                    if self.save_info[save_num].sync_request
-                        && !self.sync_queue.contains(&save_num)
-                        && !self.save_info[save_num].syncing
-                    {
-                        self.save_info[save_num].syncing = true;
+                    if self.save_info[save_num].cancel_request {
+                        self.save_info[save_num].cancel_request = false;
+                        cancel_requests.push(save_num);
+                    }
+                    if self.save_info[save_num].sync_request {
                         self.save_info[save_num].sync_request = false;
-                        self.sync_queue.push(save_num);
+                        sync_requests.push(save_num);
+                    }
+                    if let Some(direction) = self.save_info[save_num].resolve_request.take() {
+                        resolve_requests.push((save_num, direction));
+                    }
+                    if self.save_info[save_num].preview_request {
+                        self.save_info[save_num].preview_request = false;
+                        preview_requests.push(save_num);
+                    }
+                    if let Some(link) = self.save_info[save_num].link_request.take() {
+                        link_requests.push((save_num, link));
+                    }
+                    if self.save_info[save_num].revert_request {
+                        self.save_info[save_num].revert_request = false;
+                        revert_requests.push(save_num);
                     }
                     save_num += 1;
                 }
-                for save_num in &mut to_remove {
+                for save_num in cancel_requests {
+                    self.cancel_sync(save_num);
+                }
+                for save_num in sync_requests {
+                    self.queue_sync(save_num, Priority::High);
+                }
+                for (save_num, direction) in resolve_requests {
+                    self.queue_job(save_num, Priority::High, Some(direction), false, None);
+                }
+                for save_num in preview_requests {
+                    self.queue_job(save_num, Priority::High, None, true, None);
+                }
+                for (save_num, link) in link_requests {
+                    self.queue_job(save_num, Priority::High, None, false, Some(link));
+                }
+                for save_num in revert_requests {
+                    self.revert_restore(save_num);
+                }
+                for save_num in &to_remove {
+                    self.scheduler.cancel(&self.saves[*save_num].id);
+                }
+                // Remove from the back so earlier indices stay valid.
+                for save_num in to_remove.iter().rev() {
                     self.save_info.remove(*save_num);
                     self.saves.remove(*save_num);
                 }
-                let _ = data::save_config_data(self.server.clone(), &self.ftp, &self.saves);
-                if self.sync_queue.len() > 0 {
-                    let mut thread_num = 0;
-                    for t in &self.threads {
-                        let result = t.receiver.try_recv();
-                        println!("Initialising work on thread {}", thread_num);
-                        match result {
-                            Ok(str) => {
-                                if str == "free".to_string() {
-                                    let save_num = self.sync_queue.remove(0);
-                                    t.sender.send(format!("sync;{}", save_num)).unwrap();
-                                    self.save_info[save_num].thread = thread_num;
-                                    if self.sync_queue.len() == 0 {
-                                        break;
-                                    }
-                                };
-                            }
-                            Err(err) => (),
-                        }
-                        thread_num += 1;
-                    }
-                }
+                self.save_config();
+                self.scheduler.dispatch(ctx);
                 if self.settings_window.open {
                     let mut ftp = settings::FTPSettings {
                         ip: self.ftp.ip.clone(),
@@ -355,23 +844,44 @@ impl eframe::App for MyApp {
                         password: self.ftp.passwd.clone(),
                         port: self.ftp.port,
                     };
-                    self.settings_window.draw(ctx, &mut ftp);
+                    let mut general = settings::GeneralSettings {
+                        device_name: self.device.name.clone(),
+                        max_jobs: self.scheduler.config.max_jobs,
+                        max_retries: self.scheduler.config.max_retries,
+                        ftp_connections: self
+                            .scheduler
+                            .config
+                            .backend_connections
+                            .get("ftp")
+                            .copied()
+                            .unwrap_or(1),
+                    };
+                    self.settings_window.draw(ctx, &mut ftp, &mut general);
+                    self.device.name = general.device_name;
+                    self.scheduler.config.max_jobs = general.max_jobs;
+                    self.scheduler.config.max_retries = general.max_retries;
+                    self.scheduler
+                        .config
+                        .backend_connections
+                        .insert("ftp".to_string(), general.ftp_connections);
                     self.ftp.ip = ftp.ip.clone();
                     self.ftp.user = ftp.user.clone();
                     self.ftp.passwd = ftp.password.clone();
                     self.ftp.port = ftp.port;
                 }
+                if self.discover_window.open {
+                    for save in self.discover_window.draw(ctx, &self.saves, &self.device) {
+                        self.saves.push(save);
+                        self.save_info.push(SaveInfo::default());
+                    }
+                }
             });
     }
 
     fn on_exit(&mut self, _: std::option::Option<&eframe::glow::Context>) {
         let _err = data::purge_tmp_folder();
-        let _err = data::save_config_data(self.server.clone(), &self.ftp, &self.saves);
-        while self.threads.len() > 0 {
-            let t = self.threads.remove(0);
-            let _ = t.sender.send("join".to_string());
-            let _ = t.join_handle.join();
-        }
+        self.save_config();
+        self.scheduler.shutdown();
         println!("Saved data");
     }
 }
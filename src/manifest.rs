@@ -0,0 +1,342 @@
+use crate::{data, paths, steam};
+use regex::Regex;
+use std::{
+    collections::HashSet,
+    env,
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+    result::Result,
+    sync::OnceLock,
+};
+use yaml_rust2::{Yaml, YamlLoader};
+
+// A single game from a Ludusavi-style manifest, reduced to what the scanner needs.
+pub struct GameEntry {
+    pub name: String,
+    pub steam_id: Option<u32>,
+    pub install_dirs: Vec<String>,
+    pub files: Vec<ManifestPath>,
+}
+
+pub struct ManifestPath {
+    pub path: String,
+    pub os: Vec<String>,
+}
+
+// Somewhere a manifest path can be resolved against: the native OS or a Proton prefix.
+struct ScanContext {
+    os: &'static str,
+    user_name: String,
+    home: PathBuf,
+    win_user: Option<PathBuf>,
+    drive_c: Option<PathBuf>,
+}
+
+pub fn load_manifest(path: &Path) -> Result<Vec<GameEntry>, Box<dyn Error>> {
+    let text = fs::read_to_string(path)?;
+    let docs = YamlLoader::load_from_str(&text)?;
+    let mut games = Vec::new();
+    let root = match docs.first().and_then(|d| d.as_hash()) {
+        Some(root) => root,
+        None => return Ok(games),
+    };
+    for (name, entry) in root {
+        let name = match name.as_str() {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+        let mut files = Vec::new();
+        if let Some(file_map) = entry["files"].as_hash() {
+            for (path, info) in file_map {
+                let path = match path.as_str() {
+                    Some(path) => path.to_string(),
+                    None => continue,
+                };
+                if let Some(tags) = info["tags"].as_vec() {
+                    if !tags.iter().any(|t| t.as_str() == Some("save")) {
+                        continue;
+                    }
+                }
+                let os = info["when"]
+                    .as_vec()
+                    .map(|when| {
+                        when.iter()
+                            .filter_map(|w| w["os"].as_str().map(|s| s.to_string()))
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                files.push(ManifestPath { path, os });
+            }
+        }
+        if files.is_empty() {
+            continue;
+        }
+        let install_dirs = entry["installDir"]
+            .as_hash()
+            .map(|dirs| {
+                dirs.keys()
+                    .filter_map(|k| k.as_str().map(|s| s.to_string()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let steam_id = match &entry["steam"]["id"] {
+            Yaml::Integer(id) => u32::try_from(*id).ok(),
+            _ => None,
+        };
+        games.push(GameEntry {
+            name,
+            steam_id,
+            install_dirs,
+            files,
+        });
+    }
+    Ok(games)
+}
+
+fn native_context() -> ScanContext {
+    let home = home::home_dir().unwrap();
+    let user_name = env::var("USER")
+        .or_else(|_| env::var("USERNAME"))
+        .unwrap_or_default();
+    if cfg!(windows) {
+        ScanContext {
+            os: "windows",
+            user_name,
+            win_user: Some(home.clone()),
+            drive_c: Some(PathBuf::from("C:\\")),
+            home,
+        }
+    } else {
+        ScanContext {
+            os: if cfg!(target_os = "macos") {
+                "mac"
+            } else {
+                "linux"
+            },
+            user_name,
+            win_user: None,
+            drive_c: None,
+            home,
+        }
+    }
+}
+
+fn proton_context(prefix: &Path) -> ScanContext {
+    let drive_c = prefix.join("drive_c");
+    let user = drive_c.join("users").join("steamuser");
+    ScanContext {
+        os: "windows",
+        user_name: "steamuser".to_string(),
+        home: user.clone(),
+        win_user: Some(user),
+        drive_c: Some(drive_c),
+    }
+}
+
+fn xdg_dir(var: &str, fallback: &str) -> PathBuf {
+    match env::var(var) {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => home::home_dir().unwrap().join(fallback),
+    }
+}
+
+// Replaces Ludusavi placeholders, returning None if the path uses one we can't resolve here.
+fn expand_placeholders(
+    path: &str,
+    ctx: &ScanContext,
+    base: Option<(&Path, &str)>,
+) -> Option<String> {
+    static PLACEHOLDER: OnceLock<Regex> = OnceLock::new();
+    let re = PLACEHOLDER.get_or_init(|| Regex::new(r"<([A-Za-z]+)>").unwrap());
+    let mut result = String::new();
+    let mut last = 0;
+    for cap in re.captures_iter(path) {
+        let whole = cap.get(0).unwrap();
+        result.push_str(&path[last..whole.start()]);
+        last = whole.end();
+        let value = match &cap[1] {
+            "home" => ctx.home.clone(),
+            "root" => base?.0.to_path_buf(),
+            "game" => PathBuf::from(base?.1),
+            "base" => base?.0.join(base?.1),
+            "storeUserId" => PathBuf::from("*"),
+            "osUserName" => PathBuf::from(&ctx.user_name),
+            "winAppData" => ctx.win_user.as_ref()?.join("AppData/Roaming"),
+            "winLocalAppData" => ctx.win_user.as_ref()?.join("AppData/Local"),
+            "winLocalAppDataLow" => ctx.win_user.as_ref()?.join("AppData/LocalLow"),
+            "winDocuments" => ctx.win_user.as_ref()?.join("Documents"),
+            "winPublic" => ctx.drive_c.as_ref()?.join("Users/Public"),
+            "winProgramData" => ctx.drive_c.as_ref()?.join("ProgramData"),
+            "winDir" => ctx.drive_c.as_ref()?.join("Windows"),
+            "xdgData" if ctx.os == "linux" => xdg_dir("XDG_DATA_HOME", ".local/share"),
+            "xdgConfig" if ctx.os == "linux" => xdg_dir("XDG_CONFIG_HOME", ".config"),
+            _ => return None,
+        };
+        result.push_str(&value.display().to_string());
+    }
+    result.push_str(&path[last..]);
+    Some(result)
+}
+
+fn glob_to_regex(component: &str) -> Regex {
+    let mut pattern = String::from("(?i)^");
+    for c in component.chars() {
+        match c {
+            '*' => pattern.push_str(".*"),
+            '?' => pattern.push('.'),
+            _ => pattern.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    pattern.push('$');
+    Regex::new(&pattern).unwrap()
+}
+
+// Expands `*` and `?` in any path component against what actually exists on disk.
+fn expand_glob(pattern: &str) -> Vec<PathBuf> {
+    let pattern = pattern.replace('\\', "/");
+    let mut current = vec![if pattern.starts_with('/') {
+        PathBuf::from("/")
+    } else {
+        PathBuf::new()
+    }];
+    for component in pattern.split('/').filter(|c| !c.is_empty()) {
+        let mut next = Vec::new();
+        for dir in &current {
+            if component.contains('*') || component.contains('?') {
+                let re = glob_to_regex(component);
+                if let Ok(entries) = fs::read_dir(dir) {
+                    for entry in entries.flatten() {
+                        if re.is_match(&entry.file_name().to_string_lossy()) {
+                            next.push(entry.path());
+                        }
+                    }
+                }
+            } else {
+                let path = if component.ends_with(':') {
+                    PathBuf::from(format!("{component}/"))
+                } else {
+                    dir.join(component)
+                };
+                if path.exists() {
+                    next.push(path);
+                }
+            }
+        }
+        current = next;
+        if current.is_empty() {
+            break;
+        }
+    }
+    current
+}
+
+// Folders that hold far more than one game's saves. A manifest path that matches files
+// directly inside one of these, like `<xdgConfig>/Game.cfg`, would otherwise make the whole
+// folder a save.
+fn shared_folders(ctx: &ScanContext) -> Vec<PathBuf> {
+    let mut folders = vec![ctx.home.clone()];
+    if let Some(user) = &ctx.win_user {
+        for dir in [
+            "AppData/Roaming",
+            "AppData/Local",
+            "AppData/LocalLow",
+            "Documents",
+        ] {
+            folders.push(user.join(dir));
+        }
+    }
+    if let Some(drive_c) = &ctx.drive_c {
+        for dir in ["Users/Public", "ProgramData", "Windows"] {
+            folders.push(drive_c.join(dir));
+        }
+    }
+    if ctx.os == "linux" {
+        folders.push(xdg_dir("XDG_DATA_HOME", ".local/share"));
+        folders.push(xdg_dir("XDG_CONFIG_HOME", ".config"));
+        folders.push(xdg_dir("XDG_DOCUMENTS_DIR", "Documents"));
+    }
+    folders
+}
+
+// Finds save folders on this machine for every game in the manifest.
+pub fn scan(games: &[GameEntry]) -> Vec<data::SaveUI> {
+    let libraries = steam::library_folders();
+    let native = native_context();
+    let native_shared = shared_folders(&native);
+    let mut seen = HashSet::new();
+    let mut found = Vec::new();
+    for game in games {
+        let mut prefixes = Vec::new();
+        if let Some(id) = game.steam_id {
+            for library in &libraries {
+                let prefix = library
+                    .join("steamapps")
+                    .join("compatdata")
+                    .join(id.to_string())
+                    .join("pfx");
+                if prefix.exists() {
+                    let ctx = proton_context(&prefix);
+                    let shared = shared_folders(&ctx);
+                    prefixes.push((ctx, shared));
+                }
+            }
+        }
+        let common: Vec<PathBuf> = libraries
+            .iter()
+            .map(|l| l.join("steamapps").join("common"))
+            .collect();
+        // `<base>/*.sav` would make the whole install folder a save.
+        let installs: Vec<PathBuf> = common
+            .iter()
+            .flat_map(|root| game.install_dirs.iter().map(|dir| root.join(dir)))
+            .collect();
+        let contexts = prefixes.iter().map(|(ctx, shared)| (ctx, shared));
+        for (ctx, shared) in std::iter::once((&native, &native_shared)).chain(contexts) {
+            for file in &game.files {
+                if !file.os.is_empty() && !file.os.iter().any(|os| os == ctx.os) {
+                    continue;
+                }
+                let mut expanded = Vec::new();
+                if file.path.contains("<base>")
+                    || file.path.contains("<root>")
+                    || file.path.contains("<game>")
+                {
+                    for root in &common {
+                        for dir in &game.install_dirs {
+                            expanded.extend(expand_placeholders(
+                                &file.path,
+                                ctx,
+                                Some((root, dir)),
+                            ));
+                        }
+                    }
+                } else {
+                    expanded.extend(expand_placeholders(&file.path, ctx, None));
+                }
+                for pattern in expanded {
+                    for path in expand_glob(&pattern) {
+                        let dir = if path.is_file() {
+                            path.parent().unwrap().to_path_buf()
+                        } else {
+                            path
+                        };
+                        // These, or a folder holding one of them like a drive root, are
+                        // never a useful save folder.
+                        let mut too_broad = native_shared.iter().chain(shared).chain(&installs);
+                        if too_broad.any(|folder| folder.starts_with(&dir)) {
+                            continue;
+                        }
+                        if seen.insert(dir.clone()) {
+                            found.push(data::SaveUI::new(
+                                game.name.clone(),
+                                paths::contract(&dir.display().to_string()),
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+    }
+    found
+}
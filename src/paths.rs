@@ -0,0 +1,140 @@
+use crate::steam;
+use std::{env, path::PathBuf};
+
+// Placeholders that can be used in a save path so one config works on several machines.
+pub const VARIABLES: [&str; 5] = ["home", "documents", "appdata", "xdg_data", "steam"];
+
+fn env_dir(var: &str) -> Option<PathBuf> {
+    match env::var(var) {
+        Ok(dir) if !dir.is_empty() => Some(PathBuf::from(dir)),
+        _ => None,
+    }
+}
+
+// Resolves a single variable for this machine. Windows-only locations fall back to
+// their closest XDG equivalent elsewhere so the same entry still points somewhere sensible.
+pub fn resolve(variable: &str) -> Option<PathBuf> {
+    let home = home::home_dir()?;
+    match variable {
+        "home" => Some(home),
+        "documents" => Some(env_dir("XDG_DOCUMENTS_DIR").unwrap_or(home.join("Documents"))),
+        "appdata" => Some(if cfg!(windows) {
+            env_dir("APPDATA").unwrap_or(home.join("AppData").join("Roaming"))
+        } else {
+            env_dir("XDG_CONFIG_HOME").unwrap_or(home.join(".config"))
+        }),
+        "xdg_data" => Some(if cfg!(windows) {
+            env_dir("LOCALAPPDATA").unwrap_or(home.join("AppData").join("Local"))
+        } else {
+            env_dir("XDG_DATA_HOME").unwrap_or(home.join(".local").join("share"))
+        }),
+        "steam" => steam::steam_roots().into_iter().next(),
+        _ => None,
+    }
+}
+
+// Expands every `{variable}` in a save path. Unknown variables are left as they are.
+pub fn expand(path: &str) -> String {
+    let mut result = path.to_string();
+    for variable in VARIABLES {
+        let placeholder = format!("{{{}}}", variable);
+        if result.contains(&placeholder) {
+            if let Some(value) = resolve(variable) {
+                result = result.replace(&placeholder, &value.display().to_string());
+            }
+        }
+    }
+    result
+}
+
+// Rewrites an absolute path to use the most specific variable that prefixes it.
+pub fn contract(path: &str) -> String {
+    let values: Vec<(&str, String)> = VARIABLES
+        .into_iter()
+        .filter_map(|variable| Some((variable, resolve(variable)?.display().to_string())))
+        .collect();
+    contract_with(path, &values)
+}
+
+fn contract_with(path: &str, values: &[(&str, String)]) -> String {
+    let mut best: Option<(usize, String)> = None;
+    for (variable, value) in values {
+        if value.is_empty() || !path.starts_with(value.as_str()) {
+            continue;
+        }
+        // Only match whole components, so {home} doesn't swallow "/home/user2".
+        let rest = &path[value.len()..];
+        if !rest.is_empty() && !rest.starts_with(['/', '\\']) {
+            continue;
+        }
+        if best.as_ref().is_none_or(|(len, _)| value.len() > *len) {
+            best = Some((value.len(), format!("{{{}}}{}", variable, rest)));
+        }
+    }
+    match best {
+        Some((_, contracted)) => contracted,
+        None => path.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values() -> Vec<(&'static str, String)> {
+        vec![
+            ("home", "/home/user".to_string()),
+            ("appdata", "/home/user/.config".to_string()),
+            ("xdg_data", "/home/user/.local/share".to_string()),
+            ("steam", "/home/user/.local/share/Steam".to_string()),
+            ("documents", String::new()),
+        ]
+    }
+
+    #[test]
+    fn uses_the_longest_matching_variable() {
+        let values = values();
+        assert_eq!(
+            contract_with("/home/user/.local/share/Steam/steamapps", &values),
+            "{steam}/steamapps"
+        );
+        assert_eq!(
+            contract_with("/home/user/.local/share/game", &values),
+            "{xdg_data}/game"
+        );
+        assert_eq!(contract_with("/home/user/saves", &values), "{home}/saves");
+    }
+
+    #[test]
+    fn only_matches_whole_components() {
+        let values = values();
+        assert_eq!(
+            contract_with("/home/user2/saves", &values),
+            "/home/user2/saves"
+        );
+        assert_eq!(
+            contract_with("/home/user/.configs/game", &values),
+            "{home}/.configs/game"
+        );
+    }
+
+    #[test]
+    fn matches_the_variable_itself_and_windows_separators() {
+        assert_eq!(contract_with("/home/user", &values()), "{home}");
+        let values = vec![("appdata", r"C:\Users\me\AppData\Roaming".to_string())];
+        assert_eq!(
+            contract_with(r"C:\Users\me\AppData\Roaming\Game", &values),
+            r"{appdata}\Game"
+        );
+    }
+
+    #[test]
+    fn leaves_other_paths_alone() {
+        let values = values();
+        assert_eq!(
+            contract_with("/mnt/games/saves", &values),
+            "/mnt/games/saves"
+        );
+        assert_eq!(contract_with("", &values), "");
+    }
+}
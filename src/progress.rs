@@ -0,0 +1,115 @@
+use crate::worker::{Event, EventSender};
+use std::{
+    io::{self, Read},
+    time::{Duration, Instant},
+};
+
+// How often byte counts are sent to the UI, so large transfers don't flood the channel.
+const REPORT_INTERVAL: Duration = Duration::from_millis(100);
+
+pub struct Progress<'a> {
+    events: &'a EventSender,
+    stage: String,
+    done: u64,
+    total: u64,
+    last_report: Option<Instant>,
+}
+
+impl<'a> Progress<'a> {
+    pub fn new(events: &'a EventSender, stage: &str, total: u64) -> Self {
+        let mut progress = Self {
+            events,
+            stage: stage.to_string(),
+            done: 0,
+            total,
+            last_report: None,
+        };
+        progress.report();
+        progress
+    }
+
+    pub fn advance(&mut self, bytes: u64) {
+        self.done += bytes;
+        let due = self
+            .last_report
+            .is_none_or(|last| last.elapsed() >= REPORT_INTERVAL);
+        if due || self.done >= self.total {
+            self.report();
+        }
+    }
+
+    fn report(&mut self) {
+        self.last_report = Some(Instant::now());
+        let _ = self.events.send(Event::Progress {
+            stage: self.stage.clone(),
+            done: self.done,
+            total: self.total,
+        });
+    }
+}
+
+// Files, archives and transfers are copied through buffers this big and never read whole,
+// so memory use doesn't grow with the size of a save.
+pub const COPY_BUFFER: usize = 256 * 1024;
+
+// Counts bytes as they are read and reports them through a `Progress`.
+pub struct ProgressReader<'a, 'b, R> {
+    inner: R,
+    progress: &'b mut Progress<'a>,
+}
+
+impl<'a, 'b, R: Read> ProgressReader<'a, 'b, R> {
+    pub fn new(inner: R, progress: &'b mut Progress<'a>) -> Self {
+        Self { inner, progress }
+    }
+}
+
+impl<R: Read> Read for ProgressReader<'_, '_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.progress.events.check_cancelled()?;
+        let read = self.inner.read(buf)?;
+        self.progress.advance(read as u64);
+        Ok(read)
+    }
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[unit])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 3600 {
+        format!("{}h {}m", secs / 3600, (secs % 3600) / 60)
+    } else if secs >= 60 {
+        format!("{}m {}s", secs / 60, secs % 60)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+// "3 hours ago" style, for timestamps in the past.
+pub fn format_age(secs: i64) -> String {
+    let (value, unit) = match secs.max(0) {
+        0..=59 => return "just now".to_string(),
+        secs @ 60..=3599 => (secs / 60, "minute"),
+        secs @ 3600..=86399 => (secs / 3600, "hour"),
+        secs => (secs / 86400, "day"),
+    };
+    if value == 1 {
+        format!("1 {} ago", unit)
+    } else {
+        format!("{} {}s ago", value, unit)
+    }
+}
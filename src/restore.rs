@@ -0,0 +1,446 @@
+use crate::{
+    data::{self, LocalRoot, SaveUI, ROOTS_DIR},
+    ignore::IgnoreRules,
+    progress::{format_bytes, COPY_BUFFER},
+    state,
+};
+use chrono::Local;
+use std::{
+    error::Error,
+    fs::{self, File},
+    io::{self, BufWriter, Read, Seek, Write},
+    path::{Component, Path, PathBuf},
+};
+use zip::{read::ZipFile, ZipArchive};
+
+// How many pre-restore backups are kept per save.
+const BACKUP_LIMIT: usize = 5;
+// Limits on what a downloaded archive may unpack to, so a hostile server can't fill the disk.
+const MAX_ENTRIES: usize = 100_000;
+const MAX_TOTAL_SIZE: u64 = 16 * 1024 * 1024 * 1024;
+
+struct Limits {
+    entries: usize,
+    total_size: u64,
+}
+
+const LIMITS: Limits = Limits {
+    entries: MAX_ENTRIES,
+    total_size: MAX_TOTAL_SIZE,
+};
+
+fn backups_dir(save_id: &str) -> PathBuf {
+    data::config_path("backups").join(save_id)
+}
+
+// Next to the save folder, so swapping it in is a rename on the same drive.
+fn staging_path(dirpath: &Path) -> PathBuf {
+    let name = dirpath
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    dirpath.with_file_name(format!(".{}.rc-staging", name))
+}
+
+// Only plain relative paths are accepted, so a hostile archive can't write outside the
+// folder it is extracted into.
+fn entry_path(file: &ZipFile) -> Result<PathBuf, Box<dyn Error>> {
+    if file.is_symlink() {
+        return Err(format!("Archive entry {} is a symlink", file.name()).into());
+    }
+    let unsafe_path = || format!("Archive entry {} has an unsafe path", file.name());
+    // Checked the way Windows would read it too, so an archive is rejected on every OS alike.
+    let name = file.name().replace('\\', "/");
+    let drive = name.as_bytes().get(1) == Some(&b':') && name.as_bytes()[0].is_ascii_alphabetic();
+    let plain = Path::new(&name)
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+    if drive || !plain {
+        return Err(unsafe_path().into());
+    }
+    file.enclosed_name().ok_or_else(|| unsafe_path().into())
+}
+
+fn extract_zip_archive(source: &Path, destination: &Path) -> Result<(), Box<dyn Error>> {
+    let archive = ZipArchive::new(File::open(source)?)?;
+    extract_entries(archive, destination, &LIMITS)
+}
+
+fn extract_entries<R: Read + Seek>(
+    mut archive: ZipArchive<R>,
+    destination: &Path,
+    limits: &Limits,
+) -> Result<(), Box<dyn Error>> {
+    if archive.len() > limits.entries {
+        return Err(format!("Archive has more than {} entries", limits.entries).into());
+    }
+    if !destination.exists() {
+        std::fs::create_dir(destination)?;
+    }
+    let mut remaining = limits.total_size;
+    for i in 0..archive.len() {
+        let file = archive.by_index(i)?;
+        let target_path = destination.join(entry_path(&file)?);
+        if file.is_dir() {
+            std::fs::create_dir_all(&target_path)?;
+            continue;
+        }
+
+        if let Some(parent_dir) = target_path.parent() {
+            std::fs::create_dir_all(parent_dir)?;
+        }
+
+        let mut output_file = BufWriter::with_capacity(COPY_BUFFER, File::create(&target_path)?);
+
+        // The sizes in the archive's headers can lie, so count what is actually written.
+        let written = io::copy(&mut file.take(remaining + 1), &mut output_file)?;
+        output_file.flush()?;
+        if written > remaining {
+            return Err(format!(
+                "Archive expands to more than {}",
+                format_bytes(limits.total_size)
+            )
+            .into());
+        }
+        remaining -= written;
+    }
+    Ok(())
+}
+
+// Checks that every file in the archive made it to disk at its full size.
+fn verify_extraction(source: &Path, destination: &Path) -> Result<(), Box<dyn Error>> {
+    let mut archive = ZipArchive::new(File::open(source)?)?;
+    for i in 0..archive.len() {
+        let file = archive.by_index(i)?;
+        if file.is_dir() {
+            continue;
+        }
+        let target_path = destination.join(entry_path(&file)?);
+        let size = fs::metadata(&target_path).map(|m| m.len()).ok();
+        if size != Some(file.size()) {
+            return Err(format!("Restored file {} is incomplete", file.name()).into());
+        }
+    }
+    Ok(())
+}
+
+fn copy_dir(from: &Path, to: &Path) -> io::Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), target)?;
+        }
+    }
+    Ok(())
+}
+
+// Renames when it can, and copies when the backup folder is on another drive.
+fn move_dir(from: &Path, to: &Path) -> io::Result<()> {
+    if fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    if let Err(err) = copy_dir(from, to) {
+        let _ = fs::remove_dir_all(to);
+        return Err(err);
+    }
+    fs::remove_dir_all(from)
+}
+
+// Ignored files never reach the cloud, so they are copied over from the backup instead of
+// disappearing with the rest of the old folder.
+fn copy_ignored(from: &Path, to: &Path, relative: &str, rules: &IgnoreRules) -> io::Result<()> {
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        // The backups of the extra folders, handled on their own.
+        if relative.is_empty() && name == ROOTS_DIR {
+            continue;
+        }
+        let path = if relative.is_empty() {
+            name.clone()
+        } else {
+            format!("{}/{}", relative, name)
+        };
+        let target = to.join(&name);
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            if rules.is_ignored(&path, true, 0) {
+                if !target.exists() {
+                    copy_dir(&entry.path(), &target)?;
+                }
+            } else {
+                copy_ignored(&entry.path(), &target, &path, rules)?;
+            }
+        } else if rules.is_ignored(&path, false, metadata.len()) && !target.exists() {
+            fs::create_dir_all(to)?;
+            fs::copy(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
+fn prune_backups(save_id: &str) {
+    let mut backups = list_backups(save_id);
+    while backups.len() > BACKUP_LIMIT {
+        let _ = fs::remove_dir_all(backups.remove(0));
+    }
+}
+
+// Oldest first; the names are timestamps, so they sort by age.
+fn list_backups(save_id: &str) -> Vec<PathBuf> {
+    let mut backups: Vec<PathBuf> = match fs::read_dir(backups_dir(save_id)) {
+        Ok(entries) => entries.flatten().map(|e| e.path()).collect(),
+        Err(_) => Vec::new(),
+    };
+    backups.sort();
+    backups
+}
+
+pub fn latest_backup(save_id: &str) -> Option<PathBuf> {
+    list_backups(save_id).pop()
+}
+
+// Where a folder's part is kept inside a staging or backup folder.
+fn part_path(base: &Path, root: &LocalRoot) -> PathBuf {
+    if root.prefix.is_empty() {
+        base.to_path_buf()
+    } else {
+        base.join(&root.prefix)
+    }
+}
+
+// Undoes the moves made so far, newest first.
+fn undo_moves(moves: &[(PathBuf, PathBuf)]) {
+    for (from, to) in moves.iter().rev() {
+        let _ = move_dir(to, from);
+    }
+}
+
+// Splits the extra folders out of the extracted archive, each staged next to where it goes
+// so swapping it in is a rename too. Folders this device doesn't have are dropped.
+fn stage_roots(staging: &Path, roots: &[LocalRoot]) -> io::Result<Vec<PathBuf>> {
+    let mut staged = vec![staging.to_path_buf()];
+    for root in &roots[1..] {
+        let target = staging_path(&root.path);
+        if target.exists() {
+            fs::remove_dir_all(&target)?;
+        }
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let part = part_path(staging, root);
+        if part.exists() {
+            move_dir(&part, &target)?;
+        } else {
+            fs::create_dir(&target)?;
+        }
+        staged.push(target);
+    }
+    let roots_dir = staging.join(ROOTS_DIR);
+    if roots_dir.exists() {
+        fs::remove_dir_all(roots_dir)?;
+    }
+    Ok(staged)
+}
+
+// Moves every folder into the backup, then its staged copy into place. The backup mirrors
+// the archive: the main folder at the top, extra folders under `ROOTS_DIR`.
+fn swap_roots(roots: &[LocalRoot], staged: &[PathBuf], backup: &Path) -> io::Result<()> {
+    let mut moves = Vec::new();
+    let result = (|| {
+        for root in roots.iter().filter(|r| r.path.exists()) {
+            let target = part_path(backup, root);
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            move_dir(&root.path, &target)?;
+            moves.push((root.path.clone(), target));
+        }
+        for (root, staged) in roots.iter().zip(staged) {
+            if let Some(parent) = root.path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::rename(staged, &root.path)?;
+            moves.push((staged.clone(), root.path.clone()));
+        }
+        Ok(())
+    })();
+    if result.is_err() {
+        undo_moves(&moves);
+    }
+    result
+}
+
+// Extracts into a staging folder and only swaps it in once it's complete. The folders it
+// replaces are kept as a timestamped backup, so files that only existed locally aren't lost.
+pub fn restore_archive(
+    save: &SaveUI,
+    device_id: &str,
+    archive: &Path,
+) -> Result<(), Box<dyn Error>> {
+    let roots = save.local_roots(device_id);
+    // The whole archive is extracted next to the main folder.
+    let staging = staging_path(&roots[0].path);
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+    let staged = extract_zip_archive(archive, &staging)
+        .and_then(|_| verify_extraction(archive, &staging))
+        .and_then(|_| stage_roots(&staging, &roots).map_err(Into::into));
+    let staged = match staged {
+        Ok(staged) => staged,
+        Err(err) => {
+            for root in &roots {
+                let _ = fs::remove_dir_all(staging_path(&root.path));
+            }
+            return Err(err);
+        }
+    };
+    // Read before the swap, while each folder's `.rcignore` is still in place.
+    let rules: Vec<IgnoreRules> = roots
+        .iter()
+        .map(|root| IgnoreRules::for_save(save, &root.path))
+        .collect();
+    let backup = backups_dir(&save.id).join(Local::now().format("%Y-%m-%d-%H%M%S").to_string());
+    if let Err(err) = swap_roots(&roots, &staged, &backup) {
+        for staged in &staged {
+            let _ = fs::remove_dir_all(staged);
+        }
+        return Err(err.into());
+    }
+    for (root, rules) in roots.iter().zip(&rules) {
+        let previous = part_path(&backup, root);
+        if previous.exists() {
+            copy_ignored(&previous, &root.path, "", rules)?;
+        }
+    }
+    prune_backups(&save.id);
+    Ok(())
+}
+
+// Swaps one folder back for its copy from a backup.
+fn revert_root(previous: &Path, dirpath: &Path) -> Result<(), Box<dyn Error>> {
+    let discarded = staging_path(dirpath);
+    if discarded.exists() {
+        fs::remove_dir_all(&discarded)?;
+    }
+    if dirpath.exists() {
+        fs::rename(dirpath, &discarded)?;
+    }
+    if let Err(err) = move_dir(previous, dirpath) {
+        let _ = fs::rename(&discarded, dirpath);
+        return Err(err.into());
+    }
+    let _ = fs::remove_dir_all(&discarded);
+    Ok(())
+}
+
+// Puts back the folders from before the last restore. The restored copy is thrown away,
+// the server still has it. Folders the backup doesn't have are left alone.
+pub fn revert_last_restore(save: &SaveUI, device_id: &str) -> Result<(), Box<dyn Error>> {
+    let backup = latest_backup(&save.id).ok_or("No restore to revert")?;
+    let roots = save.local_roots(device_id);
+    // Extra folders first, their backups sit inside the main folder's.
+    for root in &roots[1..] {
+        let previous = part_path(&backup, root);
+        if previous.exists() {
+            revert_root(&previous, &root.path)?;
+        }
+    }
+    // Only removed when empty; anything else left there is kept with the main folder.
+    let _ = fs::remove_dir(backup.join(ROOTS_DIR));
+    revert_root(&backup, &roots[0].path)?;
+    // Otherwise the reverted folder looks like a local edit and gets uploaded over the
+    // cloud copy.
+    state::reset(&save.id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use zip::{write::SimpleFileOptions, ZipWriter};
+
+    fn archive(build: impl FnOnce(&mut ZipWriter<Cursor<Vec<u8>>>)) -> ZipArchive<Cursor<Vec<u8>>> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        build(&mut writer);
+        ZipArchive::new(writer.finish().unwrap()).unwrap()
+    }
+
+    fn with_files(files: &[(&str, &[u8])]) -> ZipArchive<Cursor<Vec<u8>>> {
+        archive(|writer| {
+            for (name, contents) in files {
+                writer
+                    .start_file(*name, SimpleFileOptions::default())
+                    .unwrap();
+                writer.write_all(contents).unwrap();
+            }
+        })
+    }
+
+    fn extract(archive: ZipArchive<Cursor<Vec<u8>>>, limits: &Limits) -> Result<(), String> {
+        let destination = std::env::temp_dir().join(format!("rc-test-{}", uuid::Uuid::new_v4()));
+        let result = extract_entries(archive, &destination, limits);
+        let _ = fs::remove_dir_all(&destination);
+        result.map_err(|err| err.to_string())
+    }
+
+    #[test]
+    fn extracts_plain_paths() {
+        let files: &[(&str, &[u8])] = &[("a.sav", b"one"), ("slot/b.sav", b"two")];
+        assert!(extract(with_files(files), &LIMITS).is_ok());
+    }
+
+    #[test]
+    fn rejects_unsafe_paths() {
+        for name in ["../x", "/abs", "C:\\x", "a/../../x", "a\\..\\..\\x"] {
+            let result = extract(with_files(&[(name, b"bad")]), &LIMITS);
+            assert!(result.is_err(), "{} was extracted", name);
+        }
+    }
+
+    #[test]
+    fn rejects_symlinks() {
+        let symlink = archive(|writer| {
+            writer
+                .add_symlink("link", "/etc/passwd", SimpleFileOptions::default())
+                .unwrap();
+        });
+        let err = extract(symlink, &LIMITS).unwrap_err();
+        assert!(err.contains("symlink"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_too_many_entries() {
+        let files: &[(&str, &[u8])] = &[("a", b"1"), ("b", b"2"), ("c", b"3")];
+        let limits = Limits {
+            entries: 2,
+            ..LIMITS
+        };
+        assert!(extract(with_files(files), &limits).is_err());
+        let limits = Limits {
+            entries: 3,
+            ..LIMITS
+        };
+        assert!(extract(with_files(files), &limits).is_ok());
+    }
+
+    #[test]
+    fn rejects_archives_that_expand_too_far() {
+        let files: &[(&str, &[u8])] = &[("a", &[0; 600]), ("b", &[0; 600])];
+        let limits = Limits {
+            total_size: 1000,
+            ..LIMITS
+        };
+        assert!(extract(with_files(files), &limits).is_err());
+        let limits = Limits {
+            total_size: 1200,
+            ..LIMITS
+        };
+        assert!(extract(with_files(files), &limits).is_ok());
+    }
+}
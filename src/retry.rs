@@ -0,0 +1,127 @@
+use ftp::FtpError;
+use rand::Rng;
+use std::{error::Error, io, time::Duration};
+
+const BASE_DELAY: Duration = Duration::from_secs(2);
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ErrorClass {
+    Transient,
+    Permanent,
+}
+
+fn classify_io(err: &io::Error) -> ErrorClass {
+    match err.kind() {
+        io::ErrorKind::TimedOut
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::ConnectionRefused
+        | io::ErrorKind::NotConnected
+        | io::ErrorKind::BrokenPipe
+        | io::ErrorKind::UnexpectedEof
+        | io::ErrorKind::WouldBlock
+        | io::ErrorKind::Interrupted => ErrorClass::Transient,
+        _ => ErrorClass::Permanent,
+    }
+}
+
+// The ftp crate puts the server's reply at the end of the message,
+// e.g. "Expected code [226], got response: 421 Too many connections".
+fn reply_code(message: &str) -> Option<u32> {
+    let reply = message.rsplit("response: ").next()?;
+    reply.get(0..3)?.parse().ok()
+}
+
+// Decides whether an error is worth retrying. Anything we don't recognise as a network
+// hiccup is treated as permanent, so bad credentials or a broken archive fail fast.
+pub fn classify(err: &(dyn Error + 'static)) -> ErrorClass {
+    if let Some(ftp_err) = err.downcast_ref::<FtpError>() {
+        return match ftp_err {
+            FtpError::ConnectionError(io_err) => classify_io(io_err),
+            FtpError::InvalidResponse(message) => match reply_code(message) {
+                Some(400..=499) => ErrorClass::Transient,
+                _ => ErrorClass::Permanent,
+            },
+            _ => ErrorClass::Permanent,
+        };
+    }
+    if let Some(io_err) = err.downcast_ref::<io::Error>() {
+        return classify_io(io_err);
+    }
+    ErrorClass::Permanent
+}
+
+// Exponential backoff with +-50% jitter, so several machines don't retry in lockstep.
+pub fn backoff_delay(attempt: u32) -> Duration {
+    let delay = BASE_DELAY
+        .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+        .min(MAX_DELAY);
+    delay.mul_f64(rand::thread_rng().gen_range(0.5..1.5))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ftp_reply(reply: &str) -> FtpError {
+        FtpError::InvalidResponse(format!("Expected code [226], got response: {}", reply))
+    }
+
+    #[test]
+    fn reads_the_reply_code_at_the_end() {
+        let message = "Expected code [226], got response: 421 Too many connections\r\n";
+        assert_eq!(reply_code(message), Some(421));
+        assert_eq!(reply_code("550 No such file"), Some(550));
+        assert_eq!(reply_code("Expected code [226], got response: "), None);
+        assert_eq!(reply_code("error: could not read reply code"), None);
+    }
+
+    #[test]
+    fn temporary_server_replies_are_retried() {
+        for reply in ["421 Too many connections", "425 Can't open data connection"] {
+            assert_eq!(
+                classify(&ftp_reply(reply)),
+                ErrorClass::Transient,
+                "{}",
+                reply
+            );
+        }
+        for reply in ["530 Login incorrect", "550 Permission denied", "garbage"] {
+            assert_eq!(
+                classify(&ftp_reply(reply)),
+                ErrorClass::Permanent,
+                "{}",
+                reply
+            );
+        }
+    }
+
+    #[test]
+    fn network_errors_are_retried() {
+        for kind in [io::ErrorKind::TimedOut, io::ErrorKind::ConnectionReset] {
+            let err = FtpError::ConnectionError(io::Error::from(kind));
+            assert_eq!(classify(&err), ErrorClass::Transient);
+            assert_eq!(classify(&io::Error::from(kind)), ErrorClass::Transient);
+        }
+        let err = io::Error::from(io::ErrorKind::PermissionDenied);
+        assert_eq!(classify(&err), ErrorClass::Permanent);
+    }
+
+    #[test]
+    fn anything_else_fails_fast() {
+        let err: Box<dyn Error> = "Archive entry ../x has an unsafe path".into();
+        assert_eq!(classify(err.as_ref()), ErrorClass::Permanent);
+        let err = FtpError::InvalidAddress("1.2.3".parse::<std::net::SocketAddr>().unwrap_err());
+        assert_eq!(classify(&err), ErrorClass::Permanent);
+    }
+
+    #[test]
+    fn backoff_grows_and_is_capped() {
+        for attempt in 1..=10 {
+            let expected = BASE_DELAY.saturating_mul(1 << (attempt - 1)).min(MAX_DELAY);
+            let delay = backoff_delay(attempt);
+            assert!(delay >= expected.mul_f64(0.5) && delay <= expected.mul_f64(1.5));
+        }
+    }
+}
@@ -0,0 +1,313 @@
+use crate::{
+    journal::Direction,
+    worker::{self, Event, EventSender, SyncJob, SyncOutcome},
+};
+use chrono::{DateTime, Local};
+use eframe::egui;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+// How many finished jobs the queue panel keeps around.
+const HISTORY_LIMIT: usize = 50;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Low,
+    Normal,
+    High,
+}
+
+impl Priority {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Priority::Low => "Low",
+            Priority::Normal => "Normal",
+            Priority::High => "High",
+        }
+    }
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct SchedulerConfig {
+    pub max_jobs: usize,
+    // How many times a sync is retried after a transient network error.
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    // Backend name -> how many connections it may have open at once.
+    pub backend_connections: HashMap<String, usize>,
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        Self {
+            max_jobs: 2,
+            max_retries: default_max_retries(),
+            backend_connections: HashMap::from([("ftp".to_string(), 2)]),
+        }
+    }
+}
+
+fn default_max_retries() -> u32 {
+    3
+}
+
+pub struct QueuedJob {
+    pub id: u64,
+    pub save_id: String,
+    pub name: String,
+    pub backend: String,
+    pub priority: Priority,
+    job: SyncJob,
+}
+
+pub struct RunningJob {
+    pub id: u64,
+    pub save_id: String,
+    pub name: String,
+    pub backend: String,
+    pub started_at: DateTime<Local>,
+    // What kind of job it is, so a retry runs the same kind.
+    force: Option<Direction>,
+    dry_run: bool,
+    started: Instant,
+    cancel: Arc<AtomicBool>,
+    handle: JoinHandle<()>,
+}
+
+impl RunningJob {
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn is_cancelling(&self) -> bool {
+        self.cancel.load(Ordering::Relaxed)
+    }
+}
+
+pub struct FinishedJob {
+    pub save_id: String,
+    pub name: String,
+    pub started_at: DateTime<Local>,
+    pub duration: Duration,
+    pub result: Result<SyncOutcome, String>,
+    pub force: Option<Direction>,
+    pub dry_run: bool,
+}
+
+// Runs sync jobs on short-lived threads, never more than the configured limits at once.
+// Workers send their events over one shared channel and wake the UI when they do.
+pub struct Scheduler {
+    pub config: SchedulerConfig,
+    next_id: u64,
+    queue: Vec<QueuedJob>,
+    running: Vec<RunningJob>,
+    history: Vec<FinishedJob>,
+    shutdown: Arc<AtomicBool>,
+    sender: Sender<(u64, Event)>,
+    receiver: Receiver<(u64, Event)>,
+}
+
+impl Scheduler {
+    pub fn new(config: SchedulerConfig) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        Self {
+            config,
+            next_id: 0,
+            queue: Vec::new(),
+            running: Vec::new(),
+            history: Vec::new(),
+            shutdown: Arc::new(AtomicBool::new(false)),
+            sender,
+            receiver,
+        }
+    }
+
+    pub fn queued(&self) -> &[QueuedJob] {
+        &self.queue
+    }
+
+    pub fn running(&self) -> &[RunningJob] {
+        &self.running
+    }
+
+    // Most recent first.
+    pub fn history(&self) -> &[FinishedJob] {
+        &self.history
+    }
+
+    pub fn clear_history(&mut self) {
+        self.history.clear();
+    }
+
+    pub fn is_active(&self, save_id: &str) -> bool {
+        self.queue.iter().any(|j| j.save_id == save_id)
+            || self.running.iter().any(|j| j.save_id == save_id)
+    }
+
+    // Queues a sync unless the save is already queued or running.
+    pub fn enqueue(&mut self, job: SyncJob, backend: &str, priority: Priority) -> bool {
+        if self.is_active(&job.save.id) {
+            return false;
+        }
+        self.next_id += 1;
+        let queued = QueuedJob {
+            id: self.next_id,
+            save_id: job.save.id.clone(),
+            name: job.save.name.clone(),
+            backend: backend.to_string(),
+            priority,
+            job,
+        };
+        // Keep the queue ordered by priority, first come first served within one.
+        let pos = self
+            .queue
+            .iter()
+            .position(|j| j.priority < priority)
+            .unwrap_or(self.queue.len());
+        self.queue.insert(pos, queued);
+        true
+    }
+
+    // Drops a queued job, or asks a running one to stop. Returns true if it was queued.
+    pub fn cancel(&mut self, save_id: &str) -> bool {
+        if let Some(pos) = self.queue.iter().position(|j| j.save_id == save_id) {
+            self.queue.remove(pos);
+            return true;
+        }
+        for job in &self.running {
+            if job.save_id == save_id {
+                job.cancel.store(true, Ordering::Relaxed);
+            }
+        }
+        false
+    }
+
+    // Swaps a queued job with its neighbour. It takes on the neighbour's priority so the
+    // queue stays ordered by priority.
+    pub fn move_queued(&mut self, job_id: u64, up: bool) {
+        let pos = match self.queue.iter().position(|j| j.id == job_id) {
+            Some(pos) => pos,
+            None => return,
+        };
+        let other = if up {
+            match pos.checked_sub(1) {
+                Some(other) => other,
+                None => return,
+            }
+        } else if pos + 1 < self.queue.len() {
+            pos + 1
+        } else {
+            return;
+        };
+        self.queue[pos].priority = self.queue[other].priority;
+        self.queue.swap(pos, other);
+    }
+
+    pub fn cancel_running(&mut self, job_id: u64) {
+        for job in &self.running {
+            if job.id == job_id {
+                job.cancel.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn backend_limit(&self, backend: &str) -> usize {
+        self.config
+            .backend_connections
+            .get(backend)
+            .copied()
+            .unwrap_or(self.config.max_jobs)
+    }
+
+    // Starts as many queued jobs as the limits allow.
+    pub fn dispatch(&mut self, ctx: &egui::Context) {
+        let mut index = 0;
+        while index < self.queue.len() && self.running.len() < self.config.max_jobs.max(1) {
+            let backend = &self.queue[index].backend;
+            let open = self
+                .running
+                .iter()
+                .filter(|j| &j.backend == backend)
+                .count();
+            if open >= self.backend_limit(backend).max(1) {
+                index += 1;
+                continue;
+            }
+            let queued = self.queue.remove(index);
+            let cancel = Arc::new(AtomicBool::new(false));
+            let events = EventSender::new(
+                queued.id,
+                self.sender.clone(),
+                ctx.clone(),
+                cancel.clone(),
+                self.shutdown.clone(),
+            );
+            let job = queued.job;
+            let (force, dry_run) = (job.force, job.dry_run);
+            let handle = thread::Builder::new()
+                .name(format!("Sync job {}", queued.id))
+                .spawn(move || worker::run(job, events))
+                .unwrap();
+            self.running.push(RunningJob {
+                id: queued.id,
+                save_id: queued.save_id,
+                name: queued.name,
+                backend: queued.backend,
+                started_at: Local::now(),
+                force,
+                dry_run,
+                started: Instant::now(),
+                cancel,
+                handle,
+            });
+        }
+    }
+
+    // Collects every event sent since the last frame, tagged with the save it belongs to.
+    pub fn poll(&mut self) -> Vec<(String, Event)> {
+        let mut events = Vec::new();
+        while let Ok((job_id, event)) = self.receiver.try_recv() {
+            let pos = match self.running.iter().position(|j| j.id == job_id) {
+                Some(pos) => pos,
+                None => continue,
+            };
+            let save_id = self.running[pos].save_id.clone();
+            if let Event::Finished(result) = &event {
+                let job = self.running.remove(pos);
+                let _ = job.handle.join();
+                self.history.insert(
+                    0,
+                    FinishedJob {
+                        save_id: job.save_id,
+                        name: job.name,
+                        started_at: job.started_at,
+                        duration: job.started.elapsed(),
+                        result: result.clone(),
+                        force: job.force,
+                        dry_run: job.dry_run,
+                    },
+                );
+                self.history.truncate(HISTORY_LIMIT);
+            }
+            events.push((save_id, event));
+        }
+        events
+    }
+
+    // Stops every job but leaves their transfers journaled, to be resumed on next start.
+    pub fn shutdown(&mut self) {
+        self.queue.clear();
+        self.shutdown.store(true, Ordering::Relaxed);
+        for job in self.running.drain(..) {
+            let _ = job.handle.join();
+        }
+    }
+}
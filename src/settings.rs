@@ -7,9 +7,18 @@ pub struct FTPSettings {
     pub password: String,
     pub port: u16,
 }
+pub struct GeneralSettings {
+    // Shown to other devices, in conflicts and in the cloud folder's device list.
+    pub device_name: String,
+    pub max_jobs: usize,
+    pub max_retries: u32,
+    pub ftp_connections: usize,
+}
 pub struct SettingsWindow {
     pub open: bool,
     current_tab_index: usize,
+    // The device name being typed. It only replaces the real one while it isn't blank.
+    device_name: Option<String>,
 }
 
 impl Default for SettingsWindow {
@@ -17,12 +26,18 @@ impl Default for SettingsWindow {
         Self {
             open: false,
             current_tab_index: 0,
+            device_name: None,
         }
     }
 }
 
 impl SettingsWindow {
-    pub fn draw(&mut self, ctx: &egui::Context, ftp_settings: &mut FTPSettings) {
+    pub fn draw(
+        &mut self,
+        ctx: &egui::Context,
+        ftp_settings: &mut FTPSettings,
+        general_settings: &mut GeneralSettings,
+    ) {
         ctx.show_viewport_immediate(
             egui::ViewportId::from_hash_of("immediate_viewport"),
             egui::ViewportBuilder::default()
@@ -76,7 +91,30 @@ impl SettingsWindow {
                         match self.current_tab_index {
                             // General
                             0 => {
-                                ui.label("General");
+                                let device_name = self
+                                    .device_name
+                                    .get_or_insert_with(|| general_settings.device_name.clone());
+                                ui.horizontal(|ui| {
+                                    ui.label("Device name: ");
+                                    ui.text_edit_singleline(device_name);
+                                });
+                                if !device_name.trim().is_empty() {
+                                    general_settings.device_name = device_name.trim().to_string();
+                                }
+                                ui.horizontal(|ui| {
+                                    ui.label("Parallel syncs: ");
+                                    ui.add(
+                                        egui::DragValue::new(&mut general_settings.max_jobs)
+                                            .range(1..=16),
+                                    );
+                                });
+                                ui.horizontal(|ui| {
+                                    ui.label("Retries: ");
+                                    ui.add(
+                                        egui::DragValue::new(&mut general_settings.max_retries)
+                                            .range(0..=10),
+                                    );
+                                });
                             }
                             // FTP
                             1 => {
@@ -92,6 +130,13 @@ impl SettingsWindow {
                                     ui.label("Password: ");
                                     ui.text_edit_singleline(&mut ftp_settings.password);
                                 });
+                                ui.horizontal(|ui| {
+                                    ui.label("Connections: ");
+                                    ui.add(
+                                        egui::DragValue::new(&mut general_settings.ftp_connections)
+                                            .range(1..=8),
+                                    );
+                                });
                             }
                             // OneDrive
                             2 => {
@@ -105,6 +150,9 @@ impl SettingsWindow {
                 if ctx.input(|i| i.viewport().close_requested()) {
                     self.open = false;
                 }
+                if !self.open {
+                    self.device_name = None;
+                }
             },
         );
     }
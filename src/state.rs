@@ -0,0 +1,55 @@
+use crate::data;
+use std::{collections::HashMap, error::Error, fs, path::PathBuf, sync::Mutex};
+
+const STATE_FILE: &str = "sync_state.json";
+
+static LOCK: Mutex<()> = Mutex::new(());
+
+// What a save looked like on this device when it last synced. Comparing against it tells
+// local and remote changes apart without trusting anyone's clock.
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct SyncState {
+    // The remote version this device last uploaded or downloaded.
+    pub version: u64,
+    pub content_hash: String,
+    // Newest modification time in the folder at that point. Only a hint: while it is
+    // unchanged the folder isn't hashed again.
+    pub time: f64,
+}
+
+fn state_path() -> PathBuf {
+    data::config_path(STATE_FILE)
+}
+
+fn read() -> HashMap<String, SyncState> {
+    fs::read(state_path())
+        .ok()
+        .and_then(|data| serde_json::from_slice(&data).ok())
+        .unwrap_or_default()
+}
+
+pub fn get(save_id: &str) -> Option<SyncState> {
+    let _guard = LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    read().remove(save_id)
+}
+
+// Makes the next sync see both copies as changed, so it asks which one to keep instead of
+// publishing whatever is in the folder now.
+pub fn reset(save_id: &str) -> Result<(), Box<dyn Error>> {
+    set(
+        save_id,
+        SyncState {
+            version: 0,
+            content_hash: String::new(),
+            time: 0.0,
+        },
+    )
+}
+
+pub fn set(save_id: &str, state: SyncState) -> Result<(), Box<dyn Error>> {
+    let _guard = LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let mut states = read();
+    states.insert(save_id.to_string(), state);
+    fs::write(state_path(), serde_json::to_vec(&states)?)?;
+    Ok(())
+}
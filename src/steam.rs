@@ -0,0 +1,397 @@
+use crate::{data, paths};
+use std::{
+    collections::HashSet,
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+    result::Result,
+};
+
+// Folders Wine creates in every prefix, which never hold a game's saves on their own.
+const PREFIX_DEFAULT_DIRS: [&str; 6] = [
+    "Microsoft",
+    "Temp",
+    "openvr",
+    "Desktop",
+    "Downloads",
+    "desktop.ini",
+];
+
+pub enum Vdf {
+    Value(String),
+    Object(Vec<(String, Vdf)>),
+}
+
+impl Vdf {
+    pub fn get(&self, key: &str) -> Option<&Vdf> {
+        match self {
+            Vdf::Object(entries) => entries
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(key))
+                .map(|(_, v)| v),
+            Vdf::Value(_) => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Vdf::Value(value) => Some(value),
+            Vdf::Object(_) => None,
+        }
+    }
+
+    pub fn entries(&self) -> &[(String, Vdf)] {
+        match self {
+            Vdf::Object(entries) => entries,
+            Vdf::Value(_) => &[],
+        }
+    }
+}
+
+pub struct InstalledGame {
+    pub app_id: u32,
+    pub name: String,
+    pub install_dir: PathBuf,
+    pub library: PathBuf,
+}
+
+impl InstalledGame {
+    pub fn proton_prefix(&self) -> Option<PathBuf> {
+        let prefix = self
+            .library
+            .join("steamapps")
+            .join("compatdata")
+            .join(self.app_id.to_string())
+            .join("pfx");
+        if prefix.exists() {
+            Some(prefix)
+        } else {
+            None
+        }
+    }
+}
+
+fn tokenize(text: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                let mut token = String::new();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => {
+                            if let Some(escaped) = chars.next() {
+                                token.push(match escaped {
+                                    'n' => '\n',
+                                    't' => '\t',
+                                    other => other,
+                                });
+                            }
+                        }
+                        '"' => break,
+                        _ => token.push(c),
+                    }
+                }
+                tokens.push(token);
+            }
+            '{' | '}' => tokens.push(c.to_string()),
+            '/' if chars.peek() == Some(&'/') => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+    tokens
+}
+
+fn parse_object(tokens: &mut std::vec::IntoIter<String>) -> Vec<(String, Vdf)> {
+    let mut entries = Vec::new();
+    while let Some(key) = tokens.next() {
+        if key == "}" {
+            break;
+        }
+        match tokens.next() {
+            Some(value) if value == "{" => entries.push((key, Vdf::Object(parse_object(tokens)))),
+            Some(value) => entries.push((key, Vdf::Value(value))),
+            None => break,
+        }
+    }
+    entries
+}
+
+// Parses Valve's KeyValues text format used by libraryfolders.vdf and appmanifest files.
+pub fn parse_vdf(text: &str) -> Vdf {
+    Vdf::Object(parse_object(&mut tokenize(text).into_iter()))
+}
+
+// Resolves symlinks so the same library is only listed once. On Windows canonicalize returns
+// verbatim `\\?\C:\...` paths that don't match manifest or user paths, so the prefix is dropped.
+fn real_path(path: &Path) -> Option<PathBuf> {
+    let real = fs::canonicalize(path).ok()?;
+    if cfg!(windows) {
+        let text = real.to_string_lossy();
+        if let Some(rest) = text.strip_prefix(r"\\?\UNC\") {
+            return Some(PathBuf::from(format!(r"\\{}", rest)));
+        }
+        if let Some(rest) = text.strip_prefix(r"\\?\") {
+            return Some(PathBuf::from(rest));
+        }
+    }
+    Some(real)
+}
+
+pub fn steam_roots() -> Vec<PathBuf> {
+    let home = home::home_dir().unwrap();
+    let candidates = [
+        home.join(".steam/steam"),
+        home.join(".local/share/Steam"),
+        home.join(".var/app/com.valvesoftware.Steam/.local/share/Steam"),
+        home.join("Library/Application Support/Steam"),
+        PathBuf::from("C:\\Program Files (x86)\\Steam"),
+    ];
+    let mut seen = HashSet::new();
+    let mut roots = Vec::new();
+    for candidate in candidates {
+        if let Some(real) = real_path(&candidate) {
+            if real.join("steamapps").exists() && seen.insert(real.clone()) {
+                roots.push(real);
+            }
+        }
+    }
+    roots
+}
+
+// Every Steam library on this machine, including the ones listed in libraryfolders.vdf.
+pub fn library_folders() -> Vec<PathBuf> {
+    let mut seen = HashSet::new();
+    let mut libraries = Vec::new();
+    for root in steam_roots() {
+        let mut found = vec![root.clone()];
+        let vdf_path = root.join("steamapps").join("libraryfolders.vdf");
+        if let Ok(text) = fs::read_to_string(&vdf_path) {
+            let vdf = parse_vdf(&text);
+            if let Some(folders) = vdf.get("libraryfolders") {
+                for (_, folder) in folders.entries() {
+                    // Old versions store the path directly, newer ones in a "path" key.
+                    let path = folder
+                        .as_str()
+                        .or_else(|| folder.get("path").and_then(|p| p.as_str()));
+                    if let Some(path) = path {
+                        found.push(PathBuf::from(path));
+                    }
+                }
+            }
+        }
+        for library in found {
+            if let Some(real) = real_path(&library) {
+                if real.join("steamapps").exists() && seen.insert(real.clone()) {
+                    libraries.push(real);
+                }
+            }
+        }
+    }
+    libraries
+}
+
+fn read_app_manifest(path: &Path, library: &Path) -> Result<InstalledGame, Box<dyn Error>> {
+    let vdf = parse_vdf(&fs::read_to_string(path)?);
+    let state = vdf.get("AppState").ok_or("Missing AppState")?;
+    let app_id = state
+        .get("appid")
+        .and_then(|v| v.as_str())
+        .ok_or("Missing appid")?
+        .parse()?;
+    let name = state
+        .get("name")
+        .and_then(|v| v.as_str())
+        .ok_or("Missing name")?
+        .to_string();
+    let install_dir = state
+        .get("installdir")
+        .and_then(|v| v.as_str())
+        .ok_or("Missing installdir")?;
+    Ok(InstalledGame {
+        app_id,
+        name,
+        install_dir: library.join("steamapps").join("common").join(install_dir),
+        library: library.to_path_buf(),
+    })
+}
+
+// Also returns a note for every app manifest that couldn't be read.
+pub fn installed_games() -> (Vec<InstalledGame>, Vec<String>) {
+    let mut games = Vec::new();
+    let mut skipped = Vec::new();
+    for library in library_folders() {
+        let entries = match fs::read_dir(library.join("steamapps")) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.flatten() {
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            if file_name.starts_with("appmanifest_") && file_name.ends_with(".acf") {
+                match read_app_manifest(&entry.path(), &library) {
+                    Ok(game) => games.push(game),
+                    Err(err) => skipped.push(format!("{}: {}", file_name, err)),
+                }
+            }
+        }
+    }
+    games.sort_by(|a, b| a.name.cmp(&b.name));
+    (games, skipped)
+}
+
+// Folders inside a Proton prefix that the game has written to.
+pub fn prefix_save_dirs(prefix: &Path) -> Vec<PathBuf> {
+    let user = prefix.join("drive_c").join("users").join("steamuser");
+    let locations = [
+        user.join("AppData").join("Roaming"),
+        user.join("AppData").join("Local"),
+        user.join("AppData").join("LocalLow"),
+        user.join("Documents"),
+        user.join("Documents").join("My Games"),
+        user.join("Saved Games"),
+    ];
+    let mut dirs = Vec::new();
+    for location in &locations {
+        let entries = match fs::read_dir(location) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let name = entry.file_name().to_string_lossy().to_string();
+            if !path.is_dir() || PREFIX_DEFAULT_DIRS.contains(&name.as_str()) {
+                continue;
+            }
+            if locations.contains(&path) {
+                continue;
+            }
+            dirs.push(path);
+        }
+    }
+    dirs
+}
+
+// Builds save entries for every installed game with a Proton prefix, along with the
+// manifests that were skipped.
+pub fn scan_saves() -> (Vec<data::SaveUI>, Vec<String>) {
+    let mut saves = Vec::new();
+    let (games, skipped) = installed_games();
+    for game in games {
+        let prefix = match game.proton_prefix() {
+            Some(prefix) => prefix,
+            None => continue,
+        };
+        for dir in prefix_save_dirs(&prefix) {
+            saves.push(data::SaveUI::new(
+                game.name.clone(),
+                paths::contract(&dir.display().to_string()),
+            ));
+        }
+    }
+    (saves, skipped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_nested_objects() {
+        let vdf = parse_vdf(
+            r#"
+            "libraryfolders"
+            {
+                "0"
+                {
+                    "path"		"/home/user/.steam/steam"
+                    "apps"
+                    {
+                        "620"		"12345"
+                    }
+                }
+                "1"		"/mnt/games"
+            }
+            "#,
+        );
+        let folders = vdf.get("libraryfolders").unwrap();
+        assert_eq!(folders.entries().len(), 2);
+        let first = folders.get("0").unwrap();
+        assert_eq!(
+            first.get("path").and_then(Vdf::as_str),
+            Some("/home/user/.steam/steam")
+        );
+        let apps = first.get("apps").unwrap();
+        assert_eq!(apps.get("620").and_then(Vdf::as_str), Some("12345"));
+        assert_eq!(folders.get("1").and_then(Vdf::as_str), Some("/mnt/games"));
+    }
+
+    #[test]
+    fn keys_are_case_insensitive() {
+        let vdf = parse_vdf(r#""AppState" { "appid" "620" "InstallDir" "Portal 2" }"#);
+        let state = vdf.get("appstate").unwrap();
+        assert_eq!(
+            state.get("installdir").and_then(Vdf::as_str),
+            Some("Portal 2")
+        );
+        assert!(state.get("name").is_none());
+    }
+
+    #[test]
+    fn handles_escapes() {
+        let vdf = parse_vdf(r#""path" "C:\\Games\\Steam" "quote" "say \"hi\"" "lines" "a\nb\tc""#);
+        assert_eq!(
+            vdf.get("path").and_then(Vdf::as_str),
+            Some(r"C:\Games\Steam")
+        );
+        assert_eq!(vdf.get("quote").and_then(Vdf::as_str), Some(r#"say "hi""#));
+        assert_eq!(vdf.get("lines").and_then(Vdf::as_str), Some("a\nb\tc"));
+    }
+
+    #[test]
+    fn skips_comments() {
+        let vdf = parse_vdf(
+            r#"
+            // A comment with "quotes" and { braces }
+            "a" "1" // trailing
+            "b" "2"
+            "#,
+        );
+        assert_eq!(vdf.entries().len(), 2);
+        assert_eq!(vdf.get("b").and_then(Vdf::as_str), Some("2"));
+    }
+
+    #[test]
+    fn keeps_slashes_inside_values() {
+        let vdf = parse_vdf(r#""url" "https://example.com/a""#);
+        assert_eq!(
+            vdf.get("url").and_then(Vdf::as_str),
+            Some("https://example.com/a")
+        );
+    }
+
+    #[test]
+    fn tolerates_truncated_input() {
+        let vdf = parse_vdf(r#""a" { "b" "1" "c""#);
+        let a = vdf.get("a").unwrap();
+        assert_eq!(a.get("b").and_then(Vdf::as_str), Some("1"));
+        assert!(a.get("c").is_none());
+        assert!(parse_vdf("").entries().is_empty());
+        assert!(parse_vdf(r#""unterminated"#).entries().is_empty());
+    }
+
+    #[test]
+    fn values_have_no_entries() {
+        let vdf = parse_vdf(r#""a" "1""#);
+        let value = vdf.get("a").unwrap();
+        assert!(value.get("x").is_none());
+        assert!(value.entries().is_empty());
+        assert!(vdf.as_str().is_none());
+    }
+}
@@ -1,45 +1,462 @@
+use crate::{
+    data::{self, SyncMode},
+    ignore::IgnoreRules,
+    journal::{self, Direction, Transfer},
+    lock,
+    progress::{format_bytes, Progress, ProgressReader, COPY_BUFFER},
+    restore,
+    state::{self, SyncState},
+    worker::{Event, EventSender, SyncJob, SyncOutcome},
+};
 use chrono::offset::Local;
-use ftp::FtpStream;
+use ftp::{
+    types::{FileType, Line},
+    FtpError, FtpStream,
+};
 use pathdiff;
+use sha2::{Digest, Sha256};
 use std::{
+    collections::{HashMap, HashSet},
     error::Error,
-    f64,
-    fs::{self, File},
-    io::{self, Read, Write},
+    f64, fs,
+    io::{self, BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write},
+    net::TcpStream,
     path::{Path, PathBuf},
     result::Result,
-    str::from_utf8,
-    sync::mpsc,
-    time::UNIX_EPOCH,
+    time::{Duration, UNIX_EPOCH},
 };
-use zip::{write::FileOptions, CompressionMethod, ZipArchive, ZipWriter};
-
-const CONFIG_DIR: &str = ".rc";
+use zip::{write::FileOptions, CompressionMethod, ZipWriter};
 
 #[derive(serde::Serialize, serde::Deserialize)]
 pub struct SaveData {
     time: f64,
+    #[serde(default)]
+    name: String,
+    // Goes up by one with every upload, whichever device made it.
+    #[serde(default)]
+    version: u64,
+    #[serde(default)]
+    device_id: String,
+    #[serde(default)]
+    device_name: String,
+    #[serde(default)]
+    app_version: String,
+    // Unix time of the upload.
+    #[serde(default)]
+    uploaded: i64,
+    // What the save folder held: file count, total size and a hash of paths and contents.
+    #[serde(default)]
+    file_count: usize,
+    #[serde(default)]
+    total_size: u64,
+    #[serde(default)]
+    content_hash: String,
+    // Every file in the archive, and the files removed from the save in earlier versions.
+    #[serde(default)]
+    files: Vec<FileEntry>,
+    #[serde(default)]
+    deleted: Vec<Tombstone>,
+    // The archive this manifest publishes, with its size and SHA-256 once uploaded.
+    #[serde(default)]
+    archive: String,
+    #[serde(default)]
+    size: u64,
+    #[serde(default)]
+    hash: String,
+}
+
+impl SaveData {
+    // Manifests from before files were listed can't say what the save holds.
+    fn lists_files(&self) -> bool {
+        !self.files.is_empty() || (self.file_count == 0 && self.uploaded != 0)
+    }
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct FileEntry {
+    path: String,
+    size: u64,
+    hash: String,
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct Tombstone {
+    path: String,
+    // The version that no longer had the file.
+    version: u64,
+    device_name: String,
+}
+
+// Maps remote save folders, keyed by save id, to their display name. Aliases are the ids
+// other devices created for the same save before they found it here.
+#[derive(serde::Serialize, serde::Deserialize, Default)]
+pub struct RemoteIndex {
+    saves: HashMap<String, RemoteSave>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct RemoteSave {
+    name: String,
+    #[serde(default)]
+    aliases: Vec<String>,
+}
+
+// Every device that syncs, keyed by device id.
+#[derive(serde::Serialize, serde::Deserialize, Default)]
+pub struct DeviceRegistry {
+    devices: HashMap<String, RemoteDevice>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct RemoteDevice {
+    name: String,
+    app_version: String,
+    last_seen: i64,
+}
+
+impl DeviceRegistry {
+    // Devices can be renamed, so the registry's name wins over the one in a manifest.
+    fn device_name(&self, data: &SaveData) -> String {
+        match self.devices.get(&data.device_id) {
+            Some(device) => device.name.clone(),
+            None => data.device_name.clone(),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum PlanAction {
+    Upload,
+    Download,
+    UpToDate,
+    // Both sides changed, but to the same content; only the sync record is updated.
+    Adopt,
+    Conflict(String),
+    // A change the save's direction mode doesn't copy.
+    Skipped(String),
+}
+
+// What a sync would do, worked out before anything is transferred. The file lists are
+// relative paths, as seen by the side being written to.
+#[derive(Clone)]
+pub struct SyncPlan {
+    pub action: PlanAction,
+    pub added: Vec<String>,
+    pub changed: Vec<String>,
+    pub deleted: Vec<String>,
+    // False when a manifest predates file lists, so the lists above can't be worked out.
+    pub files_listed: bool,
+    pub bytes: u64,
+}
+
+impl SyncPlan {
+    fn new(action: PlanAction) -> Self {
+        Self {
+            action,
+            added: Vec::new(),
+            changed: Vec::new(),
+            deleted: Vec::new(),
+            files_listed: true,
+            bytes: 0,
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        let direction = match &self.action {
+            PlanAction::Upload => "Upload to cloud",
+            PlanAction::Download => "Download from cloud",
+            PlanAction::UpToDate | PlanAction::Adopt => return "Already up to date".to_string(),
+            PlanAction::Conflict(reason) => return format!("Conflict: {}", reason),
+            PlanAction::Skipped(reason) => return format!("Skipped: {}", reason),
+        };
+        if !self.files_listed {
+            return format!("{}, {} to move", direction, format_bytes(self.bytes));
+        }
+        format!(
+            "{}: {} to add, {} to change, {} to delete, {} to move",
+            direction,
+            self.added.len(),
+            self.changed.len(),
+            self.deleted.len(),
+            format_bytes(self.bytes)
+        )
+    }
 }
 
-fn get_filenames(directory: &Path) -> Result<Vec<String>, Box<dyn Error>> {
+const INDEX_FILE: &str = "index.json";
+const DEVICES_FILE: &str = "devices.json";
+const APP_VERSION: &str = env!("CARGO_PKG_VERSION");
+// How stale a device's `last_seen` may get before the registry is written just to update it.
+const SEEN_INTERVAL: i64 = 24 * 60 * 60;
+// A pull that would delete more of the local files than this is held back as a conflict.
+const MAX_DELETE_PERCENT: usize = 50;
+const MAX_TOMBSTONES: usize = 1000;
+// Every save folder holds this one manifest, pointing at archives named by their hash.
+const MANIFEST_FILE: &str = "manifest.json";
+const FTP_TIMEOUT: Duration = Duration::from_secs(30);
+// Unfinished uploads older than this are assumed abandoned.
+const STALE_PART_AGE: i64 = 24 * 60 * 60;
+
+// A file from one of the save's folders, with its path inside the archive.
+struct LocalFile {
+    path: String,
+    full: PathBuf,
+}
+
+// Lists the files of every folder of the save. Extra folders that don't exist on this
+// device yet are skipped.
+fn list_files(job: &SyncJob) -> Result<Vec<LocalFile>, Box<dyn Error>> {
+    let mut files = Vec::new();
+    for root in job.save.local_roots(&job.device.id) {
+        if !root.path.exists() {
+            continue;
+        }
+        let rules = IgnoreRules::for_save(&job.save, &root.path);
+        for p in get_filenames(&root.path, &rules)? {
+            let relative = relative_path(&root.path, &p);
+            // Reserved for the extra folders, in case an old restore left one behind.
+            if root.prefix.is_empty() && relative.starts_with(&format!("{}/", data::ROOTS_DIR)) {
+                continue;
+            }
+            files.push(LocalFile {
+                path: root.archive_path(&relative),
+                full: PathBuf::from(p),
+            });
+        }
+    }
+    Ok(files)
+}
+
+// Lists a folder's files, leaving out whatever its ignore rules exclude.
+fn get_filenames(directory: &Path, rules: &IgnoreRules) -> Result<Vec<String>, Box<dyn Error>> {
     let mut filenames = Vec::new();
-    let paths = fs::read_dir(&directory)?;
+    collect_filenames(directory, directory, rules, &mut filenames)?;
+    Ok(filenames)
+}
+
+fn collect_filenames(
+    root: &Path,
+    directory: &Path,
+    rules: &IgnoreRules,
+    filenames: &mut Vec<String>,
+) -> Result<(), Box<dyn Error>> {
+    let paths = fs::read_dir(directory)?;
     for path_result in paths {
         let path = path_result?.path();
+        let relative = relative_path(root, &path.display().to_string());
         if path.is_dir() {
-            filenames.extend(get_filenames(&path)?);
-        } else if path.is_file() {
+            // Like git, nothing inside an ignored folder can be brought back.
+            if !rules.is_ignored(&relative, true, 0) {
+                collect_filenames(root, &path, rules, filenames)?;
+            }
+        } else if path.is_file() && !rules.is_ignored(&relative, false, fs::metadata(&path)?.len())
+        {
             filenames.push(path.display().to_string());
         }
     }
-    Ok(filenames)
+    Ok(())
 }
 
-fn get_max_mod_time(filenames: &Vec<String>) -> Result<f64, Box<dyn Error>> {
+// Uses `/` on every OS, so manifests written on Windows compare with ones from Linux.
+fn relative_path(directory: &Path, path: &str) -> String {
+    let local_path = pathdiff::diff_paths(path, directory).unwrap();
+    local_path.to_string_lossy().replace('\\', "/")
+}
+
+fn file_entries(local_files: &[LocalFile]) -> Result<Vec<FileEntry>, Box<dyn Error>> {
+    let mut files = Vec::new();
+    for file in local_files {
+        files.push(FileEntry {
+            path: file.path.clone(),
+            size: fs::metadata(&file.full)?.len(),
+            hash: file_hash(&file.full)?,
+        });
+    }
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(files)
+}
+
+// Covers each file's path and contents, so two copies of a save can be compared without
+// archive metadata getting in the way.
+fn content_hash(files: &[FileEntry]) -> String {
+    let mut hasher = Sha256::new();
+    for file in files {
+        hasher.update(file.path.as_bytes());
+        hasher.update([0]);
+        hasher.update(file.hash.as_bytes());
+        hasher.update([b'\n']);
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+// Carries the previous version's tombstones forward and adds one for every file it had
+// that this version doesn't.
+fn carry_tombstones(previous: Option<&SaveData>, data: &SaveData) -> Vec<Tombstone> {
+    let current: HashSet<&str> = data.files.iter().map(|f| f.path.as_str()).collect();
+    let mut deleted = match previous {
+        Some(previous) => previous.deleted.clone(),
+        None => Vec::new(),
+    };
+    deleted.retain(|t| !current.contains(t.path.as_str()));
+    for file in previous.iter().flat_map(|p| &p.files) {
+        if !current.contains(file.path.as_str()) {
+            deleted.push(Tombstone {
+                path: file.path.clone(),
+                version: data.version,
+                device_name: data.device_name.clone(),
+            });
+        }
+    }
+    // The oldest go first once there are too many.
+    let excess = deleted.len().saturating_sub(MAX_TOMBSTONES);
+    deleted.drain(..excess);
+    deleted
+}
+
+// Compares the files a transfer would write with the ones already on the other side.
+// Either list is None when a manifest doesn't record its files.
+fn diff_files(
+    action: PlanAction,
+    source: Option<&[FileEntry]>,
+    destination: Option<&[FileEntry]>,
+    bytes: u64,
+) -> SyncPlan {
+    let mut plan = SyncPlan::new(action);
+    plan.bytes = bytes;
+    let (source, destination) = match (source, destination) {
+        (Some(source), Some(destination)) => (source, destination),
+        _ => {
+            plan.files_listed = false;
+            return plan;
+        }
+    };
+    let existing: HashMap<&str, &str> = destination
+        .iter()
+        .map(|f| (f.path.as_str(), f.hash.as_str()))
+        .collect();
+    for file in source {
+        match existing.get(file.path.as_str()) {
+            None => plan.added.push(file.path.clone()),
+            Some(hash) if *hash != file.hash => plan.changed.push(file.path.clone()),
+            Some(_) => {}
+        }
+    }
+    let incoming: HashSet<&str> = source.iter().map(|f| f.path.as_str()).collect();
+    for file in destination {
+        if !incoming.contains(file.path.as_str()) {
+            plan.deleted.push(file.path.clone());
+        }
+    }
+    plan
+}
+
+// Decides what a sync does from the local folder, the published manifest and the record
+// of the last sync, without touching either side. `local.files` must be filled in unless
+// neither side changed.
+pub fn plan_sync(
+    mode: SyncMode,
+    force: Option<Direction>,
+    local: &SaveData,
+    server: Option<&SaveData>,
+    state: Option<&SyncState>,
+    registry: &DeviceRegistry,
+) -> SyncPlan {
+    let server_data = match server {
+        Some(server_data) => server_data,
+        None if !mode.allows(Direction::Upload) => {
+            return SyncPlan::new(PlanAction::Skipped("no cloud copy yet".to_string()))
+        }
+        None => {
+            return diff_files(
+                PlanAction::Upload,
+                Some(&local.files),
+                Some(&[]),
+                local.total_size,
+            )
+        }
+    };
+    let (local_changed, remote_changed) = match state {
+        Some(s) => (
+            s.content_hash != local.content_hash,
+            s.version != server_data.version,
+        ),
+        // A fresh install with nothing synced yet takes the cloud copy.
+        None if local.file_count == 0 => (false, true),
+        // Without a record of the last sync, modification times hint at the newer copy.
+        None if local.time > server_data.time => (true, false),
+        None if local.time < server_data.time => (false, true),
+        None => (true, true),
+    };
+    // An empty folder, such as a fresh install on another device, never replaces the cloud copy.
+    let local_changed = local_changed && local.file_count > 0;
+    // Saves last synced before version counters; matching mtimes is the best hint.
+    let legacy_match =
+        state.is_none() && server_data.content_hash.is_empty() && server_data.time == local.time;
+    let same_content = legacy_match
+        || (!server_data.content_hash.is_empty() && server_data.content_hash == local.content_hash);
+    let direction = match (force, local_changed, remote_changed) {
+        (Some(direction), _, _) => direction,
+        (None, true, false) => Direction::Upload,
+        (None, false, true) => Direction::Download,
+        (None, false, false) => return SyncPlan::new(PlanAction::UpToDate),
+        (None, true, true) if same_content => return SyncPlan::new(PlanAction::Adopt),
+        // One-way saves have no conflicts, the side they copy from always wins.
+        (None, true, true) => match mode {
+            SyncMode::TwoWay => {
+                return SyncPlan::new(PlanAction::Conflict(format!(
+                    "changed here and on {} (version {})",
+                    registry.device_name(server_data),
+                    server_data.version
+                )))
+            }
+            SyncMode::UploadOnly => Direction::Upload,
+            SyncMode::DownloadOnly => Direction::Download,
+        },
+    };
+    if !mode.allows(direction) {
+        let reason = match direction {
+            Direction::Upload => "local changes aren't uploaded in download-only mode",
+            Direction::Download => "cloud changes aren't downloaded in upload-only mode",
+        };
+        return SyncPlan::new(PlanAction::Skipped(reason.to_string()));
+    }
+    let remote_files = Some(server_data.files.as_slice()).filter(|_| server_data.lists_files());
+    match direction {
+        Direction::Upload => diff_files(
+            PlanAction::Upload,
+            Some(&local.files),
+            remote_files,
+            local.total_size,
+        ),
+        Direction::Download => {
+            let bytes = match server_data.size {
+                0 => server_data.total_size,
+                size => size,
+            };
+            let mut plan = diff_files(
+                PlanAction::Download,
+                remote_files,
+                Some(&local.files),
+                bytes,
+            );
+            // Losing most of the save is more likely a broken upload than intended.
+            if force.is_none() && plan.deleted.len() * 100 > local.file_count * MAX_DELETE_PERCENT {
+                plan.action = PlanAction::Conflict(format!(
+                    "the cloud copy from {} would delete {} of {} files",
+                    registry.device_name(server_data),
+                    plan.deleted.len(),
+                    local.file_count
+                ));
+            }
+            plan
+        }
+    }
+}
+
+fn get_max_mod_time(files: &[LocalFile]) -> Result<f64, Box<dyn Error>> {
     let mut max = 0.0;
-    for p in filenames {
-        let path = Path::new(&p);
-        let file = fs::File::open(&path)?;
+    for p in files {
+        let file = fs::File::open(&p.full)?;
         let file_max = file
             .metadata()?
             .modified()?
@@ -53,162 +470,1233 @@ fn get_max_mod_time(filenames: &Vec<String>) -> Result<f64, Box<dyn Error>> {
 }
 
 fn create_zip_archive(
+    channel: &EventSender,
     name: &String,
-    srcpath: &PathBuf,
+    files: &[LocalFile],
     destination: &mut PathBuf,
 ) -> Result<(), Box<dyn Error>> {
     destination.push(name);
-    let zip_path = fs::File::create(&destination)?;
+    let zip_path = BufWriter::with_capacity(COPY_BUFFER, fs::File::create(&destination)?);
     let mut zip_file = ZipWriter::new(zip_path);
     let options: zip::write::FileOptions<zip::write::ExtendedFileOptions> =
         FileOptions::default().compression_method(CompressionMethod::DEFLATE);
-    let filenames = get_filenames(&srcpath)?;
-    for p in &filenames {
-        let path = Path::new(&p);
-        let local_path = pathdiff::diff_paths(&path, &srcpath).unwrap();
-        zip_file.start_file(
-            local_path.into_os_string().into_string().unwrap(),
-            options.clone(),
-        )?;
-        let mut buffer = Vec::new();
-        let file = fs::File::open(&path)?;
-        io::copy(&mut file.take(u64::MAX), &mut buffer)?;
-        zip_file.write_all(&buffer)?;
-    }
-    zip_file.finish()?;
+    let mut total = 0;
+    for p in files {
+        total += fs::metadata(&p.full)?.len();
+    }
+    let mut progress = Progress::new(channel, "Compressing save", total);
+    for p in files {
+        if let Err(err) = channel.check_cancelled() {
+            drop(zip_file);
+            let _ = fs::remove_file(&destination);
+            return Err(err.into());
+        }
+        zip_file.start_file(p.path.as_str(), options.clone())?;
+        let file = fs::File::open(&p.full)?;
+        if let Err(err) = io::copy(&mut ProgressReader::new(file, &mut progress), &mut zip_file) {
+            drop(zip_file);
+            let _ = fs::remove_file(&destination);
+            return Err(err.into());
+        }
+    }
+    // Dropping the writer would flush too, but without reporting a failed write.
+    zip_file.finish()?.flush()?;
     destination.pop();
     Ok(())
 }
 
-fn extract_zip_archive(source: &Path, destination: &Path) -> Result<(), Box<dyn Error>> {
-    let zip_file = File::open(source)?;
-    let mut archive = ZipArchive::new(zip_file)?;
-    if !destination.exists() {
-        std::fs::create_dir(destination)?;
+// Writes a command the ftp crate doesn't have to the control connection.
+fn send_command(ftp_stream: &FtpStream, command: &str) -> Result<(), FtpError> {
+    let mut control = ftp_stream.get_ref();
+    control
+        .write_all(format!("{}\r\n", command).as_bytes())
+        .map_err(FtpError::ConnectionError)
+}
+
+// The address in a `227 Entering Passive Mode (h1,h2,h3,h4,p1,p2)` reply.
+fn passive_address(reply: &str) -> Result<String, FtpError> {
+    let invalid = || FtpError::InvalidResponse(format!("Invalid PASV response: {}", reply));
+    let start = reply.find('(').ok_or_else(invalid)?;
+    let end = reply[start..].find(')').ok_or_else(invalid)? + start;
+    let numbers: Vec<u16> = reply[start + 1..end]
+        .split(',')
+        .map(|n| n.trim().parse().map_err(|_| invalid()))
+        .collect::<Result<_, _>>()?;
+    match numbers[..] {
+        [a, b, c, d, high, low] if numbers.iter().all(|&n| n < 256) => {
+            Ok(format!("{}.{}.{}.{}:{}", a, b, c, d, high << 8 | low))
+        }
+        _ => Err(invalid()),
+    }
+}
+
+// Starts a transfer the ftp crate can't: `RETR` from `restart`, or `APPE` onto a partial
+// upload. The crate sends PASV inside its own transfer calls, which would land between REST
+// and RETR, so the data connection is opened here first. Returns None, with nothing
+// started, when the server turns the restart or the command down.
+fn resume_command(
+    ftp_stream: &mut FtpStream,
+    restart: Option<u64>,
+    command: &str,
+) -> Result<Option<TcpStream>, FtpError> {
+    send_command(ftp_stream, "PASV")?;
+    let Line(_, reply) = ftp_stream.read_response(227)?;
+    let data = TcpStream::connect(passive_address(&reply)?).map_err(FtpError::ConnectionError)?;
+    data.set_read_timeout(Some(FTP_TIMEOUT))
+        .and_then(|_| data.set_write_timeout(Some(FTP_TIMEOUT)))
+        .map_err(FtpError::ConnectionError)?;
+    if let Some(offset) = restart {
+        send_command(ftp_stream, &format!("REST {}", offset))?;
+        if ftp_stream.read_response(350).is_err() {
+            return Ok(None);
+        }
+    }
+    send_command(ftp_stream, command)?;
+    if ftp_stream.read_response_in(&[125, 150]).is_err() {
+        return Ok(None);
+    }
+    Ok(Some(data))
+}
+
+// Reads the server's reply once the data connection of a `resume_command` is closed.
+fn finish_command(ftp_stream: &mut FtpStream) -> Result<(), FtpError> {
+    ftp_stream.read_response_in(&[226, 250]).map(|_| ())
+}
+
+// Reuses the archive of an interrupted upload if the save hasn't changed since, otherwise
+// builds a new one. Returns the archive, its remote name and whether it was resumed.
+fn prepare_archive(
+    channel: &EventSender,
+    save_id: &str,
+    files: &[LocalFile],
+    time: f64,
+) -> Result<(PathBuf, String, bool), Box<dyn Error>> {
+    if let Some(transfer) = journal::find(save_id, Direction::Upload) {
+        if transfer.time == time && transfer.local.exists() {
+            channel.send(Event::Log(format!(
+                "Resuming interrupted upload of {}",
+                transfer.remote
+            )))?;
+            return Ok((transfer.local, transfer.remote, true));
+        }
+        journal::finish(save_id, Direction::Upload)?;
+    }
+    let mut destination = journal::transfers_dir()?;
+    let name = save_id.to_string() + ".zip";
+    create_zip_archive(channel, &name, files, &mut destination)?;
+    destination.push(&name);
+    let remote = file_hash(&destination)? + ".zip";
+    journal::record(Transfer {
+        save_id: save_id.to_string(),
+        direction: Direction::Upload,
+        remote: remote.clone(),
+        local: destination.clone(),
+        size: fs::metadata(&destination)?.len(),
+        time,
+    })?;
+    Ok((destination, remote, false))
+}
+
+fn file_hash(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+// Uploads under a `.part` name and only renames once the transfer finished, so a
+// cancelled upload never leaves a partial archive under the real name. When `resume` is
+// set, a `.part` left by an earlier attempt is continued instead of sent again.
+fn upload_file(
+    channel: &EventSender,
+    ftp_stream: &mut FtpStream,
+    local: &Path,
+    remote: &str,
+    resume: bool,
+) -> Result<(), Box<dyn Error>> {
+    let part = remote.to_string() + ".part";
+    let mut file = fs::File::open(local)?;
+    let total = file.metadata()?.len();
+    let offset = if resume {
+        match ftp_stream.size(&part) {
+            Ok(Some(size)) if (size as u64) < total => size as u64,
+            _ => 0,
+        }
+    } else {
+        0
+    };
+    let appending = match offset {
+        0 => None,
+        _ => resume_command(ftp_stream, None, &format!("APPE {}", part))?,
+    };
+    let mut progress = Progress::new(channel, "Uploading save", total);
+    let result = match appending {
+        Some(data) => {
+            channel.send(Event::Log(format!(
+                "Resuming upload at {}",
+                format_bytes(offset)
+            )))?;
+            file.seek(SeekFrom::Start(offset))?;
+            progress.advance(offset);
+            let mut data = BufWriter::with_capacity(COPY_BUFFER, data);
+            io::copy(&mut ProgressReader::new(file, &mut progress), &mut data)
+                .and_then(|_| data.flush())
+                .map_err(FtpError::ConnectionError)
+                .and_then(|_| {
+                    drop(data);
+                    finish_command(ftp_stream)
+                })
+        }
+        None => {
+            if offset > 0 {
+                channel.send(Event::Log(
+                    "The server can't continue uploads, starting over".to_string(),
+                ))?;
+            }
+            ftp_stream.put(&part, &mut ProgressReader::new(file, &mut progress))
+        }
+    };
+    if let Err(err) = result {
+        if channel.is_cancelled() && !channel.is_shutting_down() {
+            // The server still sends a reply for the aborted transfer; read it before cleaning up.
+            let _ = ftp_stream.read_response_in(&[226, 250, 426, 451]);
+            let _ = ftp_stream.rm(&part);
+        }
+        return Err(err.into());
+    }
+    // A server that appended to the wrong place left a part of the wrong size; start over.
+    if ftp_stream.size(&part)?.map(|size| size as u64) != Some(total) {
+        let _ = ftp_stream.rm(&part);
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Upload was incomplete").into());
+    }
+    let _ = ftp_stream.rm(remote);
+    ftp_stream.rename(&part, remote)?;
+    Ok(())
+}
+
+// Streams a remote file to disk instead of holding it in memory. What was already
+// downloaded by an interrupted attempt at the same version is kept and continued.
+fn download_file(
+    channel: &EventSender,
+    ftp_stream: &mut FtpStream,
+    save_id: &str,
+    remote: &str,
+    time: f64,
+) -> Result<PathBuf, Box<dyn Error>> {
+    let total = ftp_stream.size(remote)?.unwrap_or(0) as u64;
+    let local = journal::transfers_dir()?.join(save_id.to_string() + ".download");
+    let mut offset = match journal::find(save_id, Direction::Download) {
+        Some(t) if t.remote == remote && t.size == total && t.time == time => {
+            fs::metadata(&local).map(|m| m.len()).unwrap_or(0)
+        }
+        _ => 0,
+    };
+    if offset == total && total > 0 {
+        return Ok(local);
+    }
+    journal::record(Transfer {
+        save_id: save_id.to_string(),
+        direction: Direction::Download,
+        remote: remote.to_string(),
+        local: local.clone(),
+        size: total,
+        time,
+    })?;
+    if offset > total {
+        offset = 0;
+    }
+    let resumed = match offset {
+        0 => None,
+        _ => resume_command(ftp_stream, Some(offset), &format!("RETR {}", remote))?,
+    };
+    if offset > 0 && resumed.is_none() {
+        channel.send(Event::Log(
+            "The server can't continue downloads, starting over".to_string(),
+        ))?;
+        offset = 0;
+    } else if offset > 0 {
+        channel.send(Event::Log(format!(
+            "Resuming download at {}",
+            format_bytes(offset)
+        )))?;
+    }
+    let write = |stream: &mut dyn Read| {
+        let mut progress = Progress::new(channel, "Downloading save", total);
+        progress.advance(offset);
+        let file = if offset > 0 {
+            fs::OpenOptions::new().append(true).open(&local)
+        } else {
+            fs::File::create(&local)
+        }
+        .map_err(FtpError::ConnectionError)?;
+        let mut file = BufWriter::with_capacity(COPY_BUFFER, file);
+        io::copy(&mut ProgressReader::new(stream, &mut progress), &mut file)
+            .and_then(|_| file.flush())
+            .map_err(FtpError::ConnectionError)
+    };
+    let result = match resumed {
+        Some(data) => write(&mut BufReader::new(data)).and_then(|_| finish_command(ftp_stream)),
+        None => ftp_stream.retr(remote, write),
+    };
+    if let Err(err) = result {
+        // The partial file is kept for the next attempt.
+        return Err(err.into());
+    }
+    if fs::metadata(&local)?.len() != total {
+        journal::finish(save_id, Direction::Download)?;
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Download was incomplete").into());
+    }
+    Ok(local)
+}
+
+fn read_registry(ftp_stream: &mut FtpStream) -> Result<DeviceRegistry, Box<dyn Error>> {
+    if !ftp_stream.nlst(None)?.iter().any(|f| f == DEVICES_FILE) {
+        return Ok(DeviceRegistry::default());
+    }
+    let cursor = ftp_stream.simple_retr(DEVICES_FILE)?;
+    Ok(serde_json::from_slice(&cursor.into_inner())?)
+}
+
+// Records this device in the shared registry and returns the registry. It is only written
+// when something about this device changed, or it hasn't been seen for a while.
+fn register_device(
+    ftp_stream: &mut FtpStream,
+    device: &data::Device,
+) -> Result<DeviceRegistry, Box<dyn Error>> {
+    let mut registry = read_registry(ftp_stream)?;
+    let now = Local::now().timestamp();
+    let current = registry.devices.get(&device.id).is_some_and(|known| {
+        known.name == device.name
+            && known.app_version == APP_VERSION
+            && now - known.last_seen < SEEN_INTERVAL
+    });
+    if current {
+        return Ok(registry);
+    }
+    registry.devices.insert(
+        device.id.clone(),
+        RemoteDevice {
+            name: device.name.clone(),
+            app_version: APP_VERSION.to_string(),
+            last_seen: now,
+        },
+    );
+    let j = serde_json::to_vec(&registry)?;
+    ftp_stream.put(DEVICES_FILE, &mut Cursor::new(j))?;
+    Ok(registry)
+}
+
+// Where a save's remote folder is, or would be.
+enum RemoteFolder {
+    // Indexed under the save's id, or one it is an alias of.
+    Indexed(String),
+    // Keyed by the save's name, from before the index existed.
+    Legacy,
+    // The only folder another device indexed under the same name. Saves are often named
+    // after the game, so it is only shared once the user agrees.
+    SameName(String),
+    New,
+}
+
+// `link` is the user's answer to sharing a same-name folder, if they gave one.
+fn find_folder(
+    index: &RemoteIndex,
+    listing: &[String],
+    save_id: &str,
+    savename: &str,
+    link: Option<bool>,
+) -> RemoteFolder {
+    let existing = index
+        .saves
+        .iter()
+        .find(|(id, save)| *id == save_id || save.aliases.iter().any(|a| a == save_id));
+    if let Some((id, _)) = existing {
+        return RemoteFolder::Indexed(id.clone());
     }
-    for i in 0..archive.len() {
-        let mut file = archive.by_index(i)?;
-        let file_name = file.name().to_owned();
+    let reserved = [INDEX_FILE, DEVICES_FILE, lock::LOCK_DIR];
+    if savename.is_empty() || reserved.contains(&savename) || link == Some(false) {
+        return RemoteFolder::New;
+    }
+    if listing.iter().any(|f| f == savename) && !index.saves.contains_key(savename) {
+        return RemoteFolder::Legacy;
+    }
+    let mut same_name = index.saves.iter().filter(|(_, save)| save.name == savename);
+    match (same_name.next(), same_name.next()) {
+        (Some((id, _)), None) => RemoteFolder::SameName(id.clone()),
+        _ => RemoteFolder::New,
+    }
+}
 
-        let target_path = destination.join(file_name);
+fn read_index(
+    ftp_stream: &mut FtpStream,
+    listing: &[String],
+) -> Result<RemoteIndex, Box<dyn Error>> {
+    if !listing.iter().any(|f| f == INDEX_FILE) {
+        return Ok(RemoteIndex::default());
+    }
+    let cursor = ftp_stream.simple_retr(INDEX_FILE)?;
+    Ok(serde_json::from_slice(&cursor.into_inner())?)
+}
 
-        if let Some(parent_dir) = target_path.parent() {
-            std::fs::create_dir_all(parent_dir)?;
+// Finds the remote folder for a save, migrating folders that were keyed by name. Returns
+// None when another device's folder has the same name and the user hasn't said whether
+// to share it.
+fn resolve_remote_folder(
+    channel: &EventSender,
+    ftp_stream: &mut FtpStream,
+    job: &SyncJob,
+) -> Result<Option<String>, Box<dyn Error>> {
+    let (save_id, savename) = (&job.save.id, &job.save.name);
+    let listing = ftp_stream.nlst(None)?;
+    let mut index = read_index(ftp_stream, &listing)?;
+    let mut changed = true;
+    let folder = match find_folder(&index, &listing, save_id, savename, job.link) {
+        RemoteFolder::Indexed(folder) => {
+            changed = index.saves[&folder].name != *savename;
+            folder
+        }
+        RemoteFolder::SameName(folder) if job.link == Some(true) => {
+            channel.send(Event::Log(format!(
+                "Linked to the cloud save '{}' from another device",
+                savename
+            )))?;
+            let save = index.saves.get_mut(&folder).unwrap();
+            save.aliases.push(save_id.to_string());
+            folder
+        }
+        RemoteFolder::SameName(_) => return Ok(None),
+        legacy_or_new => {
+            if let RemoteFolder::Legacy = legacy_or_new {
+                channel.send(Event::Log(format!(
+                    "Migrated remote folder '{}' to {}",
+                    savename, save_id
+                )))?;
+                ftp_stream.rename(savename, save_id)?;
+            }
+            index.saves.insert(
+                save_id.to_string(),
+                RemoteSave {
+                    name: savename.to_string(),
+                    aliases: Vec::new(),
+                },
+            );
+            save_id.to_string()
         }
+    };
+    if changed {
+        index.saves.get_mut(&folder).unwrap().name = savename.to_string();
+        let j = serde_json::to_vec(&index)?;
+        ftp_stream.put(INDEX_FILE, &mut Cursor::new(j))?;
+    }
+    Ok(Some(folder))
+}
+
+fn read_manifest(ftp_stream: &mut FtpStream) -> Result<SaveData, Box<dyn Error>> {
+    let cursor = ftp_stream.simple_retr(MANIFEST_FILE)?;
+    Ok(serde_json::from_slice(&cursor.into_inner())?)
+}
+
+// Saves uploaded before the fixed manifest had a date-named `.json` next to each archive.
+// Returns the newest one, pointing at its archive.
+fn latest_dated_manifest(
+    ftp_stream: &mut FtpStream,
+    list: &[String],
+) -> Result<Option<(String, SaveData)>, Box<dyn Error>> {
+    let mut latest: Option<(String, SaveData)> = None;
+    for name in list.iter().filter(|f| f.ends_with(".json")) {
+        let cursor = ftp_stream.simple_retr(name)?;
+        let data: SaveData = serde_json::from_slice(&cursor.into_inner())?;
+        if latest.as_ref().is_none_or(|(_, l)| data.time > l.time) {
+            latest = Some((name.clone(), data));
+        }
+    }
+    let (name, mut data) = match latest {
+        Some(latest) => latest,
+        None => return Ok(None),
+    };
+    if data.archive.is_empty() {
+        data.archive = name.trim_end_matches(".json").to_string() + ".zip";
+    }
+    if data.size == 0 {
+        data.size = ftp_stream.size(&data.archive)?.unwrap_or(0) as u64;
+    }
+    Ok(Some((name, data)))
+}
 
-        let mut output_file = File::create(&target_path)?;
+// The newest dated manifest becomes the manifest, pointing at its archive as it is; the
+// next upload replaces that archive with a hash-named one.
+fn migrate_dated_manifest(
+    channel: &EventSender,
+    ftp_stream: &mut FtpStream,
+    list: &[String],
+) -> Result<Option<SaveData>, Box<dyn Error>> {
+    let (name, data) = match latest_dated_manifest(ftp_stream, list)? {
+        Some(latest) => latest,
+        None => return Ok(None),
+    };
+    publish_manifest(ftp_stream, MANIFEST_FILE, &data)?;
+    for item in list.iter().filter(|f| f.ends_with(".json")) {
+        let _ = ftp_stream.rm(item);
+    }
+    channel.send(Event::Log(format!(
+        "Migrated {} to {}",
+        name, MANIFEST_FILE
+    )))?;
+    Ok(Some(data))
+}
 
-        io::copy(&mut file, &mut output_file)?;
+// Removes `.part` files left by uploads that never finished. Recent ones may still be in
+// progress on another device, and the one our own journal is resuming is kept.
+fn remove_stale_parts(ftp_stream: &mut FtpStream, list: &[String], keep: Option<&str>) {
+    let now = Local::now().timestamp();
+    for name in list.iter().filter(|f| f.ends_with(".part")) {
+        if Some(name.as_str()) == keep {
+            continue;
+        }
+        let stale = match ftp_stream.mdtm(name) {
+            Ok(Some(modified)) => now - modified.timestamp() > STALE_PART_AGE,
+            _ => false,
+        };
+        if stale {
+            let _ = ftp_stream.rm(name);
+        }
+    }
+}
+
+// Other devices only read finished `.json` manifests, so the manifest goes up under a
+// temporary name and is renamed into place once its size checks out.
+fn publish_manifest(
+    ftp_stream: &mut FtpStream,
+    name: &str,
+    data: &SaveData,
+) -> Result<(), Box<dyn Error>> {
+    let part = name.to_string() + ".part";
+    let bytes = serde_json::to_vec(data)?;
+    ftp_stream.put(&part, &mut Cursor::new(&bytes))?;
+    if ftp_stream.size(&part)?.map(|size| size as u64) != Some(bytes.len() as u64) {
+        let _ = ftp_stream.rm(&part);
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "Manifest upload was incomplete",
+        )
+        .into());
+    }
+    if ftp_stream.rename(&part, name).is_err() {
+        // Some servers won't rename over an existing file.
+        let _ = ftp_stream.rm(name);
+        ftp_stream.rename(&part, name)?;
     }
     Ok(())
 }
 
-pub fn sync_save_ftp(
-    channel: &mpsc::Sender<String>,
-    save_index: usize,
-    savename: &String,
-    directory: &String,
-    address: &String,
-    username: &String,
-    password: &String,
-    port: u16,
+// Uploads a new version without touching the one other devices currently see. Each
+// version gets its own archive name, and the previous archive is only removed after the
+// manifest points at the new one.
+fn upload_save(
+    channel: &EventSender,
+    ftp_stream: &mut FtpStream,
+    job: &SyncJob,
+    data: &mut SaveData,
+    previous: Option<&SaveData>,
+    old_files: &[String],
 ) -> Result<(), Box<dyn Error>> {
-    let mut tmp = home::home_dir().unwrap();
-    tmp.push(CONFIG_DIR);
-    tmp.push("tmp");
-    if !tmp.exists() {
-        fs::create_dir(&tmp)?;
+    let save_id = &job.save.id;
+    // Hashing and compressing each read the whole save.
+    lock::extend(ftp_stream, &job.device, data.total_size * 2)?;
+    let files = list_files(job)?;
+    if data.files.is_empty() {
+        data.files = file_entries(&files)?;
     }
-    let dirpath = Path::new(&directory).to_path_buf();
-    if !dirpath.exists() {
-        return Ok(());
+    data.deleted = carry_tombstones(previous, data);
+    let (zip_path, zip_name, resume) = prepare_archive(channel, save_id, &files, data.time)?;
+    channel.send(Event::stage("Zip archive created"))?;
+    lock::extend(ftp_stream, &job.device, fs::metadata(&zip_path)?.len())?;
+    upload_file(channel, ftp_stream, &zip_path, &zip_name, resume)?;
+    lock::refresh(ftp_stream, &job.device)?;
+    data.archive = zip_name.clone();
+    data.size = fs::metadata(&zip_path)?.len();
+    data.hash = zip_name.trim_end_matches(".zip").to_string();
+    data.uploaded = Local::now().timestamp();
+    channel.send(Event::stage("Publishing save"))?;
+    publish_manifest(ftp_stream, MANIFEST_FILE, data)?;
+    journal::finish(save_id, Direction::Upload)?;
+    state::set(
+        save_id,
+        SyncState {
+            version: data.version,
+            content_hash: data.content_hash.clone(),
+            time: data.time,
+        },
+    )?;
+    for item in old_files {
+        // Parts are left to `remove_stale_parts`, another device may still be writing one.
+        if *item != zip_name
+            && item != MANIFEST_FILE
+            && item != lock::LOCK_DIR
+            && !item.ends_with(".part")
+        {
+            let _ = ftp_stream.rm(item);
+        }
+    }
+    Ok(())
+}
+
+pub fn sync_save_ftp(channel: &EventSender, job: &SyncJob) -> Result<SyncOutcome, Box<dyn Error>> {
+    let result = if job.dry_run {
+        preview_save(channel, job).and_then(|plan| {
+            channel.send(Event::Plan(Box::new(plan)))?;
+            Ok(SyncOutcome::Previewed)
+        })
+    } else {
+        sync_ftp(channel, job)
+    };
+    match result {
+        Err(_) if channel.is_cancelled() => {
+            // A cancelled transfer isn't resumed later; drop what was kept for it.
+            if !channel.is_shutting_down() {
+                journal::finish(&job.save.id, Direction::Upload)?;
+                journal::finish(&job.save.id, Direction::Download)?;
+            }
+            Ok(SyncOutcome::Cancelled)
+        }
+        Err(err) => match err.downcast::<lock::Locked>() {
+            Ok(locked) => Ok(SyncOutcome::Locked(locked.to_string())),
+            Err(err) => Err(err),
+        },
+        result => result,
     }
-    let filenames = get_filenames(&dirpath)?;
+}
+
+// Scans the save folder. The contents are only hashed when the modification times say
+// something may have changed since the last sync.
+fn local_save(job: &SyncJob, state: Option<&SyncState>) -> Result<SaveData, Box<dyn Error>> {
+    let (savename, device) = (&job.save.name, &job.device);
+    let filenames = list_files(job)?;
     let max_mod_time = get_max_mod_time(&filenames)?;
-    let data = SaveData { time: max_mod_time };
-    let j = serde_json::to_string(&data)?;
-    tmp.push(savename.to_string() + "-" + &Local::now().date_naive().to_string() + ".json");
-    fs::write(&tmp, &j).expect("Unable to write file");
-    tmp.pop();
-    channel.send("Connecting to FTP server".to_string())?;
-    let mut ftp_stream = FtpStream::connect(address.to_string() + ":" + &port.to_string())?;
-    channel.send("Logging in to FTP server".to_string())?;
-    ftp_stream.login(username, password)?;
+    let mut total_size = 0;
+    for p in &filenames {
+        total_size += fs::metadata(&p.full)?.len();
+    }
+    // The file list is filled in later if an upload needs it.
+    let (files, content_hash) = match state {
+        Some(state) if state.time == max_mod_time => (Vec::new(), state.content_hash.clone()),
+        _ => {
+            let files = file_entries(&filenames)?;
+            let hash = content_hash(&files);
+            (files, hash)
+        }
+    };
+    Ok(SaveData {
+        time: max_mod_time,
+        name: savename.to_string(),
+        version: 0,
+        device_id: device.id.clone(),
+        device_name: device.name.clone(),
+        app_version: APP_VERSION.to_string(),
+        uploaded: 0,
+        file_count: filenames.len(),
+        total_size,
+        content_hash,
+        files,
+        deleted: Vec::new(),
+        archive: String::new(),
+        size: 0,
+        hash: String::new(),
+    })
+}
+
+fn connect(channel: &EventSender, ftp: &data::FtpDetails) -> Result<FtpStream, Box<dyn Error>> {
+    channel.send(Event::stage("Connecting to FTP server"))?;
+    let mut ftp_stream = FtpStream::connect(ftp.ip.to_string() + ":" + &ftp.port.to_string())?;
+    // A stalled server should surface as a timeout we can retry, not hang the job forever.
+    ftp_stream.get_ref().set_read_timeout(Some(FTP_TIMEOUT))?;
+    ftp_stream.get_ref().set_write_timeout(Some(FTP_TIMEOUT))?;
+    channel.send(Event::stage("Logging in to FTP server"))?;
+    ftp_stream.login(&ftp.user, &ftp.passwd)?;
+    // Sizes are compared after every upload, which only works if nothing is converted.
+    ftp_stream.transfer_type(FileType::Binary)?;
+    Ok(ftp_stream)
+}
+
+// Works out what a sync would do without writing anything, locally or on the server.
+// No lock is taken, so another device may change the save before a real sync runs.
+pub fn preview_save(channel: &EventSender, job: &SyncJob) -> Result<SyncPlan, Box<dyn Error>> {
+    let (save_id, savename) = (&job.save.id, &job.save.name);
+    let dirpath = PathBuf::from(job.save.local_path(&job.device.id));
+    if !dirpath.exists() {
+        return Err(SyncOutcome::MissingFolder.describe().into());
+    }
+    // Hashes everything, so the plan can list changed files.
+    let data = local_save(job, None)?;
+    let mut ftp_stream = connect(channel, &job.ftp)?;
+    let mut registry = DeviceRegistry::default();
+    let mut manifest = None;
+    if ftp_stream
+        .nlst(None)?
+        .contains(&"raincloud-saves".to_string())
+    {
+        ftp_stream.cwd("raincloud-saves")?;
+        channel.send(Event::stage("Reading cloud save"))?;
+        registry = read_registry(&mut ftp_stream)?;
+        let listing = ftp_stream.nlst(None)?;
+        let index = read_index(&mut ftp_stream, &listing)?;
+        let folder = match find_folder(&index, &listing, save_id, savename, job.link) {
+            RemoteFolder::Indexed(folder) => Some(folder),
+            // A folder still keyed by name hasn't been migrated yet.
+            RemoteFolder::Legacy => Some(savename.clone()),
+            RemoteFolder::SameName(folder) if job.link == Some(true) => Some(folder),
+            RemoteFolder::SameName(_) => {
+                ftp_stream.quit()?;
+                return Ok(SyncPlan::new(PlanAction::Skipped(
+                    "another device uses this name, sync to choose whether to share it".to_string(),
+                )));
+            }
+            RemoteFolder::New => None,
+        };
+        if let Some(folder) = folder.filter(|f| listing.contains(f)) {
+            ftp_stream.cwd(&folder)?;
+            let list = ftp_stream.nlst(None)?;
+            manifest = if list.iter().any(|f| f == MANIFEST_FILE) {
+                Some(read_manifest(&mut ftp_stream)?)
+            } else {
+                latest_dated_manifest(&mut ftp_stream, &list)?.map(|(_, data)| data)
+            };
+        }
+    }
+    ftp_stream.quit()?;
+    let state = state::get(save_id);
+    Ok(plan_sync(
+        job.save.mode,
+        job.force,
+        &data,
+        manifest.as_ref(),
+        state.as_ref(),
+        &registry,
+    ))
+}
+
+fn sync_ftp(channel: &EventSender, job: &SyncJob) -> Result<SyncOutcome, Box<dyn Error>> {
+    let (save_id, savename, device) = (&job.save.id, &job.save.name, &job.device);
+    let dirpath = PathBuf::from(job.save.local_path(&device.id));
+    if !dirpath.exists() {
+        return Ok(SyncOutcome::MissingFolder);
+    }
+    let mut data = local_save(job, state::get(save_id).as_ref())?;
+    let mut ftp_stream = connect(channel, &job.ftp)?;
     if !ftp_stream
         .nlst(None)?
         .contains(&"raincloud-saves".to_string())
     {
-        channel.send("Making cloud folder".to_string())?;
+        channel.send(Event::stage("Making cloud folder"))?;
         ftp_stream.mkdir("raincloud-saves")?;
     }
     ftp_stream.cwd("raincloud-saves")?;
-    if !ftp_stream.nlst(None)?.contains(savename) {
-        channel.send("Making save folder".to_string())?;
-        ftp_stream.mkdir(savename)?;
+    // The registry and index are shared by every save, so they are changed under one lock.
+    let (registry, folder) = lock::with_shared(channel, &mut ftp_stream, device, |ftp_stream| {
+        let registry = register_device(ftp_stream, device)?;
+        let folder = resolve_remote_folder(channel, ftp_stream, job)?;
+        Ok((registry, folder))
+    })?;
+    let folder = match folder {
+        Some(folder) => folder,
+        None => return Ok(SyncOutcome::SameName),
+    };
+    if !ftp_stream.nlst(None)?.contains(&folder) {
+        channel.send(Event::stage("Making save folder"))?;
+        ftp_stream.mkdir(&folder)?;
     }
-    ftp_stream.cwd(savename)?;
+    ftp_stream.cwd(&folder)?;
+    channel.send(Event::stage("Locking save folder"))?;
+    lock::acquire(channel, &mut ftp_stream, device)?;
+    let result = sync_folder(channel, &mut ftp_stream, job, &registry, &mut data);
+    // Released even if the sync failed. If the connection is gone, the lock expires instead.
+    let _ = lock::release(&mut ftp_stream, device);
+    let outcome = result?;
+    ftp_stream.quit()?;
+    Ok(outcome)
+}
+
+fn sync_folder(
+    channel: &EventSender,
+    ftp_stream: &mut FtpStream,
+    job: &SyncJob,
+    registry: &DeviceRegistry,
+    data: &mut SaveData,
+) -> Result<SyncOutcome, Box<dyn Error>> {
+    let save_id = &job.save.id;
     let list = ftp_stream.nlst(None)?;
-    let mut json_f = "".to_string();
-    for f in &list {
-        if f.ends_with(".json") {
-            json_f = f.to_string();
-            break;
-        }
-    }
-    let save_filename: String = savename.to_owned() + "-" + &Local::now().date_naive().to_string();
-
-    if json_f == "".to_string() {
-        channel.send("Previous save not found, uploading save".to_string())?;
-        create_zip_archive(&(save_filename.clone() + ".zip"), &dirpath, &mut tmp)?;
-        channel.send("Zip archive created".to_string())?;
-        tmp.push(&(save_filename.clone() + ".zip"));
-        let mut zip_file = fs::File::open(&tmp)?;
-        tmp.pop();
-        tmp.push(&(save_filename.clone() + ".json"));
-        let json_file_data = serde_json::to_string(&data)?;
-        fs::write(&tmp, &json_file_data)?;
-        channel.send("Created json file.".to_string())?;
-        let mut json_file = fs::File::open(&tmp)?;
-        ftp_stream.put(&(save_filename.clone() + ".json"), &mut json_file)?;
-        ftp_stream.put(&(save_filename.clone() + ".zip"), &mut zip_file)?;
-        channel.send("Save uploaded to cloud.".to_string())?;
+    let resuming = journal::find(save_id, Direction::Upload).map(|t| t.remote + ".part");
+    remove_stale_parts(ftp_stream, &list, resuming.as_deref());
+
+    let manifest = if list.iter().any(|f| f == MANIFEST_FILE) {
+        Some(read_manifest(ftp_stream)?)
     } else {
-        channel.send("Checking date of previous save".to_string())?;
-        let cursor = ftp_stream.simple_retr(&json_f)?;
-        let vec = cursor.into_inner();
-        let file = from_utf8(&vec)?;
-        let server_data: SaveData = serde_json::from_str(&file)?;
-        if server_data.time > data.time {
-            channel.send("Downloading previous save".to_string())?;
-            tmp.push(&(save_filename.clone() + ".zip"));
-            let mut zip_file = fs::File::create(&tmp)?;
-            let cursor = ftp_stream.simple_retr(&(save_filename.clone() + ".zip"))?;
-            let vec = cursor.into_inner();
-            zip_file.write(&vec)?;
-            extract_zip_archive(&tmp, &dirpath)?;
-        } else if server_data.time == data.time {
-            channel.send("Already up to date.".to_string())?;
-            // Do nothing
-        } else {
-            channel.send("Uploading local save to cloud".to_string())?;
-            for item in ftp_stream.nlst(None)? {
-                let _ = ftp_stream.rm(&item);
+        migrate_dated_manifest(channel, ftp_stream, &list)?
+    };
+
+    let state = state::get(save_id);
+    // Hashing is skipped when the folder looks unchanged, but the plan needs the file list
+    // unless neither side changed.
+    let unchanged = job.force.is_none()
+        && matches!((&manifest, &state), (Some(m), Some(s)) if m.version == s.version);
+    if data.files.is_empty() && !unchanged {
+        data.files = file_entries(&list_files(job)?)?;
+    }
+    channel.send(Event::stage("Comparing with cloud save"))?;
+    let plan = plan_sync(
+        job.save.mode,
+        job.force,
+        data,
+        manifest.as_ref(),
+        state.as_ref(),
+        registry,
+    );
+    let outcome = match (plan.action, &manifest) {
+        (PlanAction::Upload, previous) => {
+            channel.send(Event::stage(match previous {
+                Some(_) => "Uploading local save to cloud",
+                None => "Previous save not found, uploading save",
+            }))?;
+            data.version = previous.as_ref().map_or(0, |p| p.version) + 1;
+            upload_save(channel, ftp_stream, job, data, previous.as_ref(), &list)?;
+            SyncOutcome::Uploaded
+        }
+        (PlanAction::Download, Some(server_data)) => {
+            download_save(channel, ftp_stream, job, server_data)?;
+            if !plan.deleted.is_empty() {
+                channel.send(Event::Log(describe_removed(&plan.deleted, server_data)))?;
             }
-            let save_filename: String =
-                savename.to_owned() + "-" + &Local::now().date_naive().to_string();
-            create_zip_archive(&(save_filename.clone() + ".zip"), &dirpath, &mut tmp)?;
-            tmp.push(&(save_filename.clone() + ".zip"));
-            let mut zip_file = fs::File::open(&tmp)?;
-            tmp.pop();
-            tmp.push(&(save_filename.clone() + ".json"));
-            let json_file_data = serde_json::to_string(&data)?;
-            fs::write(&tmp, &json_file_data)?;
-            let mut json_file = fs::File::open(&tmp)?;
-            ftp_stream.put(&(save_filename.clone() + ".json"), &mut json_file)?;
-            ftp_stream.put(&(save_filename.clone() + ".zip"), &mut zip_file)?;
-            channel.send("Save uploaded to cloud.".to_string())?;
+            SyncOutcome::Downloaded
+        }
+        (PlanAction::Adopt, Some(server_data)) => {
+            state::set(
+                save_id,
+                SyncState {
+                    version: server_data.version,
+                    content_hash: data.content_hash.clone(),
+                    time: data.time,
+                },
+            )?;
+            SyncOutcome::UpToDate
         }
+        (PlanAction::Conflict(reason), _) => {
+            channel.send(Event::Conflict(reason))?;
+            SyncOutcome::Conflict
+        }
+        (PlanAction::Skipped(reason), _) => SyncOutcome::Skipped(reason),
+        _ => SyncOutcome::UpToDate,
+    };
+    let published = match outcome {
+        SyncOutcome::Uploaded => Some(&*data),
+        _ => manifest.as_ref(),
+    };
+    // Manifests from before uploads were stamped don't say who made them.
+    if let Some(published) = published.filter(|d| d.uploaded != 0) {
+        channel.send(Event::LastUpload {
+            device: registry.device_name(published),
+            uploaded: published.uploaded,
+            version: published.version,
+        })?;
+    }
+    Ok(outcome)
+}
+
+fn describe_removed(removed: &[String], server_data: &SaveData) -> String {
+    let files: Vec<String> = removed
+        .iter()
+        .map(
+            |path| match server_data.deleted.iter().find(|t| &t.path == path) {
+                Some(tombstone) => format!("{} (deleted on {})", path, tombstone.device_name),
+                None => path.clone(),
+            },
+        )
+        .collect();
+    format!(
+        "Removed {} files not in the cloud copy, kept in the restore backup: {}",
+        removed.len(),
+        files.join(", ")
+    )
+}
+
+// Downloads the published version and swaps it in, then records what the folder holds now.
+fn download_save(
+    channel: &EventSender,
+    ftp_stream: &mut FtpStream,
+    job: &SyncJob,
+    server_data: &SaveData,
+) -> Result<(), Box<dyn Error>> {
+    let save_id = &job.save.id;
+    channel.send(Event::stage("Downloading previous save"))?;
+    lock::extend(ftp_stream, &job.device, server_data.size)?;
+    let zip_path = download_file(
+        channel,
+        ftp_stream,
+        save_id,
+        &server_data.archive,
+        server_data.time,
+    )?;
+    let size_matches = server_data.size == 0 || fs::metadata(&zip_path)?.len() == server_data.size;
+    let hash_matches = server_data.hash.is_empty() || file_hash(&zip_path)? == server_data.hash;
+    if !size_matches || !hash_matches {
+        journal::finish(save_id, Direction::Download)?;
+        return Err("Remote archive doesn't match its manifest".into());
+    }
+    channel.check_cancelled()?;
+    // Restoring isn't interrupted once started, it swaps the whole folder at the end.
+    channel.send(Event::stage("Restoring save"))?;
+    lock::extend(ftp_stream, &job.device, server_data.total_size * 2)?;
+    restore::restore_archive(&job.save, &job.device.id, &zip_path)?;
+    journal::finish(save_id, Direction::Download)?;
+    // Extracted files get fresh mtimes, so the folder is hashed again.
+    let restored = local_save(job, None)?;
+    state::set(
+        save_id,
+        SyncState {
+            version: server_data.version,
+            content_hash: restored.content_hash,
+            time: restored.time,
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries(files: &[(&str, &str)]) -> Vec<FileEntry> {
+        files
+            .iter()
+            .map(|(path, hash)| FileEntry {
+                path: path.to_string(),
+                size: 10,
+                hash: hash.to_string(),
+            })
+            .collect()
+    }
+
+    fn save(version: u64, time: f64, files: &[(&str, &str)]) -> SaveData {
+        let files = entries(files);
+        SaveData {
+            time,
+            name: "Save".to_string(),
+            version,
+            device_id: "other".to_string(),
+            device_name: "Other PC".to_string(),
+            app_version: APP_VERSION.to_string(),
+            uploaded: 1,
+            file_count: files.len(),
+            total_size: files.len() as u64 * 10,
+            content_hash: content_hash(&files),
+            files,
+            deleted: Vec::new(),
+            archive: String::new(),
+            size: 0,
+            hash: String::new(),
+        }
+    }
+
+    fn record(version: u64, data: &SaveData) -> SyncState {
+        SyncState {
+            version,
+            content_hash: data.content_hash.clone(),
+            time: data.time,
+        }
+    }
+
+    fn plan(
+        mode: SyncMode,
+        force: Option<Direction>,
+        local: &SaveData,
+        server: Option<&SaveData>,
+        state: Option<&SyncState>,
+    ) -> SyncPlan {
+        plan_sync(
+            mode,
+            force,
+            local,
+            server,
+            state,
+            &DeviceRegistry::default(),
+        )
+    }
+
+    const BASE: &[(&str, &str)] = &[("a.sav", "1"), ("b.sav", "2"), ("c.sav", "3")];
+    const EDITED: &[(&str, &str)] = &[("a.sav", "1"), ("b.sav", "9"), ("c.sav", "3")];
+
+    #[test]
+    fn up_to_date_when_neither_side_changed() {
+        let local = save(0, 100.0, BASE);
+        let server = save(4, 100.0, BASE);
+        let state = record(4, &local);
+        let result = plan(SyncMode::TwoWay, None, &local, Some(&server), Some(&state));
+        assert_eq!(result.action, PlanAction::UpToDate);
+    }
+
+    #[test]
+    fn uploads_local_changes() {
+        let local = save(0, 200.0, EDITED);
+        let server = save(4, 100.0, BASE);
+        let state = record(4, &server);
+        let result = plan(SyncMode::TwoWay, None, &local, Some(&server), Some(&state));
+        assert_eq!(result.action, PlanAction::Upload);
+        assert_eq!(result.changed, vec!["b.sav"]);
+        assert!(result.added.is_empty() && result.deleted.is_empty());
+    }
+
+    #[test]
+    fn uploads_everything_without_a_cloud_copy() {
+        let local = save(0, 100.0, BASE);
+        let result = plan(SyncMode::TwoWay, None, &local, None, None);
+        assert_eq!(result.action, PlanAction::Upload);
+        assert_eq!(result.added.len(), 3);
+        assert_eq!(result.bytes, 30);
+    }
+
+    #[test]
+    fn downloads_remote_changes() {
+        let local = save(0, 100.0, BASE);
+        let server = save(5, 200.0, EDITED);
+        let state = record(4, &local);
+        let result = plan(SyncMode::TwoWay, None, &local, Some(&server), Some(&state));
+        assert_eq!(result.action, PlanAction::Download);
+        assert_eq!(result.changed, vec!["b.sav"]);
+    }
+
+    #[test]
+    fn conflicts_when_both_sides_changed() {
+        let base = save(4, 100.0, BASE);
+        let local = save(0, 200.0, EDITED);
+        let server = save(5, 300.0, &[("a.sav", "7"), ("b.sav", "2"), ("c.sav", "3")]);
+        let state = record(4, &base);
+        let result = plan(SyncMode::TwoWay, None, &local, Some(&server), Some(&state));
+        assert!(matches!(result.action, PlanAction::Conflict(_)));
+    }
+
+    #[test]
+    fn adopts_when_both_sides_have_the_same_content() {
+        let base = save(4, 100.0, BASE);
+        let local = save(0, 200.0, EDITED);
+        let server = save(5, 300.0, EDITED);
+        let state = record(4, &base);
+        let result = plan(SyncMode::TwoWay, None, &local, Some(&server), Some(&state));
+        assert_eq!(result.action, PlanAction::Adopt);
+    }
+
+    #[test]
+    fn holds_back_downloads_that_delete_most_files() {
+        let local = save(0, 100.0, BASE);
+        let server = save(5, 200.0, &[("a.sav", "1")]);
+        let state = record(4, &local);
+        let result = plan(SyncMode::TwoWay, None, &local, Some(&server), Some(&state));
+        assert!(matches!(result.action, PlanAction::Conflict(_)));
+        assert_eq!(result.deleted.len(), 2);
+
+        // Losing exactly half is still allowed.
+        let local = save(0, 100.0, &[("a.sav", "1"), ("b.sav", "2")]);
+        let state = record(4, &local);
+        let result = plan(SyncMode::TwoWay, None, &local, Some(&server), Some(&state));
+        assert_eq!(result.action, PlanAction::Download);
+    }
+
+    #[test]
+    fn one_way_modes_skip_the_other_direction() {
+        let local = save(0, 100.0, BASE);
+        let server = save(5, 200.0, EDITED);
+        let state = record(4, &local);
+        let result = plan(
+            SyncMode::UploadOnly,
+            None,
+            &local,
+            Some(&server),
+            Some(&state),
+        );
+        assert!(matches!(result.action, PlanAction::Skipped(_)));
+
+        let local = save(0, 200.0, EDITED);
+        let server = save(4, 100.0, BASE);
+        let state = record(4, &server);
+        let result = plan(
+            SyncMode::DownloadOnly,
+            None,
+            &local,
+            Some(&server),
+            Some(&state),
+        );
+        assert!(matches!(result.action, PlanAction::Skipped(_)));
+
+        let result = plan(SyncMode::DownloadOnly, None, &local, None, None);
+        assert!(matches!(result.action, PlanAction::Skipped(_)));
+    }
+
+    #[test]
+    fn one_way_modes_resolve_conflicts_their_way() {
+        let base = save(4, 100.0, BASE);
+        let local = save(0, 200.0, EDITED);
+        let server = save(5, 300.0, &[("a.sav", "7"), ("b.sav", "2"), ("c.sav", "3")]);
+        let state = record(4, &base);
+        let result = plan(
+            SyncMode::UploadOnly,
+            None,
+            &local,
+            Some(&server),
+            Some(&state),
+        );
+        assert_eq!(result.action, PlanAction::Upload);
+        let result = plan(
+            SyncMode::DownloadOnly,
+            None,
+            &local,
+            Some(&server),
+            Some(&state),
+        );
+        assert_eq!(result.action, PlanAction::Download);
+    }
+
+    #[test]
+    fn forced_directions_override_the_plan() {
+        let local = save(0, 100.0, BASE);
+        let server = save(4, 100.0, BASE);
+        let state = record(4, &local);
+        let forced = Some(Direction::Upload);
+        let result = plan(
+            SyncMode::TwoWay,
+            forced,
+            &local,
+            Some(&server),
+            Some(&state),
+        );
+        assert_eq!(result.action, PlanAction::Upload);
+
+        // A forced download goes ahead even when it deletes most files.
+        let server = save(5, 200.0, &[("a.sav", "1")]);
+        let forced = Some(Direction::Download);
+        let result = plan(
+            SyncMode::TwoWay,
+            forced,
+            &local,
+            Some(&server),
+            Some(&state),
+        );
+        assert_eq!(result.action, PlanAction::Download);
+        assert_eq!(result.deleted.len(), 2);
+
+        // The mode still applies.
+        let result = plan(
+            SyncMode::UploadOnly,
+            forced,
+            &local,
+            Some(&server),
+            Some(&state),
+        );
+        assert!(matches!(result.action, PlanAction::Skipped(_)));
+    }
+
+    #[test]
+    fn legacy_manifests_plan_without_file_lists() {
+        let local = save(0, 100.0, BASE);
+        let mut server = save(0, 200.0, &[]);
+        server.file_count = 3;
+        server.total_size = 30;
+        server.uploaded = 0;
+        server.content_hash = String::new();
+        let result = plan(SyncMode::TwoWay, None, &local, Some(&server), None);
+        assert_eq!(result.action, PlanAction::Download);
+        assert!(!result.files_listed);
+        assert_eq!(result.bytes, 30);
+
+        // Matching modification times count as the same save.
+        server.time = local.time;
+        let result = plan(SyncMode::TwoWay, None, &local, Some(&server), None);
+        assert_eq!(result.action, PlanAction::Adopt);
+    }
+
+    fn remote_index(saves: &[(&str, &str)]) -> RemoteIndex {
+        let saves = saves
+            .iter()
+            .map(|(id, name)| {
+                let save = RemoteSave {
+                    name: name.to_string(),
+                    aliases: Vec::new(),
+                };
+                (id.to_string(), save)
+            })
+            .collect();
+        RemoteIndex { saves }
+    }
+
+    fn listing(index: &RemoteIndex) -> Vec<String> {
+        index.saves.keys().cloned().collect()
+    }
+
+    #[test]
+    fn folders_are_found_by_id_and_alias() {
+        let mut index = remote_index(&[("id-a", "Game"), ("id-b", "Other")]);
+        index
+            .saves
+            .get_mut("id-b")
+            .unwrap()
+            .aliases
+            .push("id-c".to_string());
+        let list = listing(&index);
+        let found = find_folder(&index, &list, "id-a", "Renamed", None);
+        assert!(matches!(found, RemoteFolder::Indexed(id) if id == "id-a"));
+        let found = find_folder(&index, &list, "id-c", "Other", Some(false));
+        assert!(matches!(found, RemoteFolder::Indexed(id) if id == "id-b"));
+    }
+
+    #[test]
+    fn same_name_folders_are_only_shared_when_asked() {
+        let index = remote_index(&[("id-a", "Game")]);
+        let list = listing(&index);
+        let found = find_folder(&index, &list, "id-b", "Game", None);
+        assert!(matches!(found, RemoteFolder::SameName(id) if id == "id-a"));
+        let found = find_folder(&index, &list, "id-b", "Game", Some(false));
+        assert!(matches!(found, RemoteFolder::New));
+
+        // Unnamed saves and names shared by several folders are never matched.
+        let index = remote_index(&[("id-a", ""), ("id-b", "Game"), ("id-c", "Game")]);
+        let list = listing(&index);
+        assert!(matches!(
+            find_folder(&index, &list, "id-d", "", None),
+            RemoteFolder::New
+        ));
+        let found = find_folder(&index, &list, "id-d", "Game", None);
+        assert!(matches!(found, RemoteFolder::New));
+    }
+
+    #[test]
+    fn legacy_folders_are_found_by_name() {
+        let index = remote_index(&[]);
+        let list = vec!["Game".to_string(), INDEX_FILE.to_string()];
+        let found = find_folder(&index, &list, "id-a", "Game", None);
+        assert!(matches!(found, RemoteFolder::Legacy));
+        let found = find_folder(&index, &list, "id-a", INDEX_FILE, None);
+        assert!(matches!(found, RemoteFolder::New));
+    }
+
+    #[test]
+    fn passive_replies_give_the_data_address() {
+        let reply = "227 Entering Passive Mode (192,168,1,20,19,137).\r\n";
+        assert_eq!(passive_address(reply).unwrap(), "192.168.1.20:5001");
+        assert!(passive_address("227 Entering Passive Mode").is_err());
+        assert!(passive_address("227 (1,2,3,4,5)").is_err());
+        assert!(passive_address("227 (1,2,3,256,5,6)").is_err());
+    }
+
+    #[test]
+    fn modification_times_decide_without_a_sync_record() {
+        let local = save(0, 200.0, EDITED);
+        let server = save(4, 100.0, BASE);
+        let result = plan(SyncMode::TwoWay, None, &local, Some(&server), None);
+        assert_eq!(result.action, PlanAction::Upload);
+
+        let local = save(0, 100.0, BASE);
+        let server = save(4, 200.0, EDITED);
+        let result = plan(SyncMode::TwoWay, None, &local, Some(&server), None);
+        assert_eq!(result.action, PlanAction::Download);
+
+        // An empty folder never replaces the cloud copy, however new it looks.
+        let local = save(0, 300.0, &[]);
+        let result = plan(SyncMode::TwoWay, None, &local, Some(&server), None);
+        assert_eq!(result.action, PlanAction::Download);
     }
-    ftp_stream.quit()?;
-    Ok(())
 }
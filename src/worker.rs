@@ -0,0 +1,198 @@
+use crate::{
+    data,
+    journal::Direction,
+    retry::{self, ErrorClass},
+    sync::{self, SyncPlan},
+};
+use eframe::egui;
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Sender},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+pub struct SyncJob {
+    pub save: data::SaveUI,
+    pub ftp: data::FtpDetails,
+    pub device: data::Device,
+    pub max_retries: u32,
+    // Set when the user settled a conflict by picking which copy to keep.
+    pub force: Option<Direction>,
+    // Only work out what the sync would do, without transferring anything.
+    pub dry_run: bool,
+    // Whether to share a cloud folder another device made under this save's name: None
+    // asks first, false always makes a folder of its own.
+    pub link: Option<bool>,
+}
+
+#[derive(Clone, PartialEq)]
+pub enum SyncOutcome {
+    Uploaded,
+    Downloaded,
+    UpToDate,
+    MissingFolder,
+    Cancelled,
+    // Both copies changed since the last sync; the user has to pick one.
+    Conflict,
+    // Another device holds the remote lock; says who and until when.
+    Locked(String),
+    // A dry run finished; the plan was sent as an event.
+    Previewed,
+    // The save's direction mode held back a change; says which.
+    Skipped(String),
+    // Another device has a cloud save with this name; the user decides whether to share it.
+    SameName,
+}
+
+impl SyncOutcome {
+    pub fn describe(&self) -> String {
+        match self {
+            SyncOutcome::Uploaded => "Save uploaded to cloud.".to_string(),
+            SyncOutcome::Downloaded => "Save downloaded from cloud.".to_string(),
+            SyncOutcome::UpToDate => "Already up to date.".to_string(),
+            SyncOutcome::MissingFolder => "Save folder does not exist.".to_string(),
+            SyncOutcome::Cancelled => "Sync cancelled.".to_string(),
+            SyncOutcome::Conflict => "Conflict: choose which copy to keep.".to_string(),
+            SyncOutcome::Locked(by) => format!("{}.", by),
+            SyncOutcome::Previewed => "Preview ready.".to_string(),
+            SyncOutcome::Skipped(reason) => format!("Skipped: {}.", reason),
+            SyncOutcome::SameName => {
+                "Another device has a cloud save with this name: link it or keep separate."
+                    .to_string()
+            }
+        }
+    }
+}
+
+pub enum Event {
+    Started,
+    // A `total` of zero means the stage has no byte count to show.
+    Progress {
+        stage: String,
+        done: u64,
+        total: u64,
+    },
+    Log(String),
+    // Who published the version now on the server, and when.
+    LastUpload {
+        device: String,
+        uploaded: i64,
+        version: u64,
+    },
+    Finished(Result<SyncOutcome, String>),
+    // Both the local and remote copy changed since the last sync.
+    Conflict(String),
+    // What a dry run found the sync would do.
+    Plan(Box<SyncPlan>),
+}
+
+impl Event {
+    pub fn stage(stage: &str) -> Self {
+        Event::Progress {
+            stage: stage.to_string(),
+            done: 0,
+            total: 0,
+        }
+    }
+}
+
+// Wakes the UI up whenever an event is sent, so it doesn't need to redraw continuously.
+// Also carries the job's cancel flag, which long-running steps poll between chunks, and
+// the scheduler's shutdown flag, which stops the job without abandoning its transfers.
+#[derive(Clone)]
+pub struct EventSender {
+    job_id: u64,
+    sender: Sender<(u64, Event)>,
+    ctx: egui::Context,
+    cancel: Arc<AtomicBool>,
+    shutdown: Arc<AtomicBool>,
+}
+
+impl EventSender {
+    pub fn new(
+        job_id: u64,
+        sender: Sender<(u64, Event)>,
+        ctx: egui::Context,
+        cancel: Arc<AtomicBool>,
+        shutdown: Arc<AtomicBool>,
+    ) -> Self {
+        Self {
+            job_id,
+            sender,
+            ctx,
+            cancel,
+            shutdown,
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.load(Ordering::Relaxed) || self.is_shutting_down()
+    }
+
+    // The app is closing; in-flight transfers should be kept so they can be resumed.
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutdown.load(Ordering::Relaxed)
+    }
+
+    pub fn check_cancelled(&self) -> io::Result<()> {
+        if self.is_cancelled() {
+            Err(io::Error::other("Sync cancelled"))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn send(&self, event: Event) -> Result<(), mpsc::SendError<(u64, Event)>> {
+        let result = self.sender.send((self.job_id, event));
+        self.ctx.request_repaint();
+        result
+    }
+}
+
+pub fn run(job: SyncJob, events: EventSender) {
+    let _ = events.send(Event::Started);
+    let mut attempt = 0;
+    let result = loop {
+        let result = sync::sync_save_ftp(&events, &job);
+        let err = match result {
+            Ok(outcome) => break Ok(outcome),
+            Err(err) => err,
+        };
+        if retry::classify(err.as_ref()) == ErrorClass::Permanent {
+            break Err(err.to_string());
+        }
+        if attempt >= job.max_retries {
+            break Err(format!("{} (gave up after {} attempts)", err, attempt + 1));
+        }
+        attempt += 1;
+        let delay = retry::backoff_delay(attempt);
+        let _ = events.send(Event::Log(format!("Attempt {} failed: {}", attempt, err)));
+        let _ = events.send(Event::stage(&format!(
+            "Retrying in {}s (attempt {} of {})",
+            delay.as_secs(),
+            attempt + 1,
+            job.max_retries + 1
+        )));
+        if !sleep_unless_cancelled(&events, delay) {
+            break Ok(SyncOutcome::Cancelled);
+        }
+    };
+    let _ = events.send(Event::Finished(result));
+}
+
+// Sleeps in short steps so a cancel doesn't have to wait out the whole backoff.
+fn sleep_unless_cancelled(events: &EventSender, duration: Duration) -> bool {
+    let deadline = Instant::now() + duration;
+    while Instant::now() < deadline {
+        if events.is_cancelled() {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    !events.is_cancelled()
+}